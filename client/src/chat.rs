@@ -0,0 +1,277 @@
+use crate::Spectating;
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use bevy_renet::run_if_client_connected;
+use renet::RenetClient;
+use store::net::CHAT_CHANNEL;
+use store::{ChatMessage, MAX_CHAT_LENGTH};
+
+// The chat panel sits to the right of the board and is this many pixels wide
+pub const CHAT_PANEL_WIDTH: f32 = 240.0;
+// The chat panel starts below the ui at the top of the window
+const CHAT_PANEL_TOP: f32 = 60.0;
+// How many messages the log shows at once. The mouse wheel scrolls back through older ones
+const LOG_LENGTH: usize = 14;
+
+/// Lets the players in a room chat with each other. Only makes sense when playing online
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ChatLog::default())
+            .insert_resource(ChatInput::default())
+            .add_event::<SendChat>()
+            // The camera is spawned during startup, so it can only be moved once startup is done
+            .add_startup_system_to_stage(StartupStage::PostStartup, setup_chat)
+            .add_system(type_chat)
+            .add_system(update_chat_input)
+            .add_system(scroll_chat_log)
+            .add_system(update_chat_log.after(scroll_chat_log))
+            .add_system(send_chat.with_run_criteria(run_if_client_connected))
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                receive_chat.with_run_criteria(run_if_client_connected),
+            );
+    }
+}
+
+/// Every chat message received, oldest first
+#[derive(Default)]
+struct ChatLog {
+    messages: Vec<ChatMessage>,
+    /// How many of the newest messages are hidden because the player scrolled back
+    scrolled_back: usize,
+}
+
+/// The message the player is typing
+#[derive(Default)]
+struct ChatInput(String);
+
+/// Sent when the player hits enter to send the message they typed
+struct SendChat(String);
+
+#[derive(Component)]
+struct ChatLogText;
+
+#[derive(Component)]
+struct ChatInputText;
+
+fn setup_chat(
+    mut commands: Commands,
+    mut camera: Query<&mut Transform, With<Camera>>,
+    asset_server: Res<AssetServer>,
+) {
+    // Move the camera over, so the board ends up to the left of the chat panel instead of underneath it
+    camera.single_mut().translation.x = CHAT_PANEL_WIDTH / 2.0;
+
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    right: Val::Px(0.0),
+                    top: Val::Px(CHAT_PANEL_TOP),
+                    bottom: Val::Px(0.0),
+                    ..default()
+                },
+                size: Size::new(Val::Px(CHAT_PANEL_WIDTH), Val::Auto),
+                ..default()
+            },
+            color: Color::hex("32302f").unwrap().into(),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(
+                    TextBundle::from_section("", chat_style(&asset_server)).with_style(Style {
+                        position_type: PositionType::Absolute,
+                        position: UiRect {
+                            left: Val::Px(8.0),
+                            top: Val::Px(8.0),
+                            ..default()
+                        },
+                        max_size: Size::new(Val::Px(CHAT_PANEL_WIDTH - 16.0), Val::Undefined),
+                        ..default()
+                    }),
+                )
+                .insert(ChatLogText);
+
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: UiRect {
+                            left: Val::Px(8.0),
+                            right: Val::Px(8.0),
+                            bottom: Val::Px(8.0),
+                            ..default()
+                        },
+                        padding: UiRect::all(Val::Px(6.0)),
+                        ..default()
+                    },
+                    color: Color::hex("3c3836").unwrap().into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(
+                            TextBundle::from_section("", chat_style(&asset_server)).with_style(
+                                Style {
+                                    max_size: Size::new(
+                                        Val::Px(CHAT_PANEL_WIDTH - 28.0),
+                                        Val::Undefined,
+                                    ),
+                                    ..default()
+                                },
+                            ),
+                        )
+                        .insert(ChatInputText);
+                });
+        });
+}
+
+fn chat_style(asset_server: &AssetServer) -> TextStyle {
+    TextStyle {
+        font: asset_server.load("Inconsolata.ttf"),
+        font_size: 16.0,
+        color: Color::hex("ebdbb2").unwrap(),
+    }
+}
+
+fn type_chat(
+    mut characters: EventReader<ReceivedCharacter>,
+    keys: Res<Input<KeyCode>>,
+    spectating: Option<Res<Spectating>>,
+    mut input: ResMut<ChatInput>,
+    mut send: EventWriter<SendChat>,
+) {
+    // Spectators only get to watch, the server won't pass on anything they say
+    if spectating.is_some() {
+        return;
+    }
+
+    for character in characters.iter() {
+        // Control characters like backspace and enter come through here too, they are handled below
+        if !character.char.is_control() && input.0.chars().count() < MAX_CHAT_LENGTH {
+            input.0.push(character.char);
+        }
+    }
+    if keys.just_pressed(KeyCode::Back) {
+        input.0.pop();
+    }
+    if keys.just_pressed(KeyCode::Return) && !input.0.trim().is_empty() {
+        send.send(SendChat(std::mem::take(&mut input.0)));
+    }
+}
+
+fn update_chat_input(
+    input: Res<ChatInput>,
+    spectating: Option<Res<Spectating>>,
+    mut text: Query<&mut Text, With<ChatInputText>>,
+) {
+    let became_spectator = spectating
+        .as_ref()
+        .map(|spectating| spectating.is_added())
+        .unwrap_or(false);
+    if !input.is_changed() && !became_spectator {
+        return;
+    }
+
+    let mut text = text.single_mut();
+    let section = &mut text.sections[0];
+    if spectating.is_some() {
+        section.value = "Spectators can't chat".to_string();
+        section.style.color = Color::hex("928374").unwrap();
+    } else if input.0.is_empty() {
+        section.value = "Type to chat...".to_string();
+        section.style.color = Color::hex("928374").unwrap();
+    } else {
+        section.value = format!("{}_", input.0);
+        section.style.color = Color::hex("ebdbb2").unwrap();
+    }
+}
+
+fn scroll_chat_log(mut wheel: EventReader<MouseWheel>, mut log: ResMut<ChatLog>) {
+    let oldest = log.messages.len().saturating_sub(LOG_LENGTH);
+    let mut scrolled_back = log.scrolled_back;
+    for event in wheel.iter() {
+        if event.y > 0.0 {
+            scrolled_back = (scrolled_back + 1).min(oldest);
+        } else if event.y < 0.0 {
+            scrolled_back = scrolled_back.saturating_sub(1);
+        }
+    }
+    // Only touch the log when it actually scrolled, so it isn't redrawn every frame
+    if scrolled_back != log.scrolled_back {
+        log.scrolled_back = scrolled_back;
+    }
+}
+
+fn update_chat_log(
+    log: Res<ChatLog>,
+    mut text: Query<&mut Text, With<ChatLogText>>,
+    asset_server: Res<AssetServer>,
+) {
+    if !log.is_changed() {
+        return;
+    }
+
+    let style = chat_style(&asset_server);
+    let end = log.messages.len() - log.scrolled_back;
+    let start = end.saturating_sub(LOG_LENGTH);
+    let mut text = text.single_mut();
+    text.sections = log.messages[start..end]
+        .iter()
+        .flat_map(|message| match &message.sender {
+            Some(sender) => vec![
+                TextSection::new(
+                    format!("{}: ", sender),
+                    TextStyle {
+                        color: Color::hex("fabd2f").unwrap(),
+                        ..style.clone()
+                    },
+                ),
+                TextSection::new(format!("{}\n", message.text), style.clone()),
+            ],
+            // Messages from the server itself are greyed out
+            None => vec![TextSection::new(
+                format!("{}\n", message.text),
+                TextStyle {
+                    color: Color::hex("928374").unwrap(),
+                    ..style.clone()
+                },
+            )],
+        })
+        .collect();
+    if log.scrolled_back > 0 {
+        text.sections.push(TextSection::new(
+            format!("({} newer, scroll down)", log.scrolled_back),
+            TextStyle {
+                color: Color::hex("928374").unwrap(),
+                ..style
+            },
+        ));
+    }
+}
+
+fn send_chat(mut send: EventReader<SendChat>, mut client: ResMut<RenetClient>) {
+    for SendChat(text) in send.iter() {
+        // The server fills in who sent the message
+        let message = ChatMessage {
+            sender: None,
+            text: text.clone(),
+        };
+        client.send_message(CHAT_CHANNEL, bincode::serialize(&message).unwrap());
+    }
+}
+
+fn receive_chat(mut client: ResMut<RenetClient>, mut log: ResMut<ChatLog>) {
+    while let Some(message) = client.receive_message(CHAT_CHANNEL) {
+        let message: ChatMessage = bincode::deserialize(&message).unwrap();
+        log.messages.push(message);
+        // Keep showing the same messages to a player who is reading back through the log
+        if log.scrolled_back > 0 {
+            log.scrolled_back += 1;
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{PlayerAction, ShowNotice};
+use bevy::prelude::*;
+use store::ai::{self, Difficulty};
+use store::net::Session;
+use store::{GameEvent, GameState, PlayerId, Rules, Stage, UserData, ValidationError};
+
+// Nobody else is in a local game, so the ids only need to be different from each other
+const PLAYER_ID: PlayerId = 1;
+const COMPUTER_ID: PlayerId = 2;
+
+/// Plays a game against a computer controlled opponent without going through a server.
+/// Moves are checked against the rules here, and the computer answers each one after a short pause.
+pub struct LocalGamePlugin {
+    pub difficulty: Difficulty,
+    pub rules: Rules,
+}
+
+impl Plugin for LocalGamePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(GameState::new(self.rules))
+            .insert_resource(Computer {
+                difficulty: self.difficulty,
+                // Let the computer think for a moment before it moves. Instant replies feel robotic
+                thinking: Timer::from_seconds(0.5, false),
+            })
+            .add_startup_system(start_local_game)
+            .add_system(play_local_game);
+    }
+}
+
+struct Computer {
+    difficulty: Difficulty,
+    thinking: Timer,
+}
+
+fn start_local_game(
+    mut commands: Commands,
+    user_data: Res<UserData>,
+    computer: Res<Computer>,
+    mut game_state: ResMut<GameState>,
+    mut game_events: EventWriter<GameEvent>,
+) {
+    commands.insert_resource(Session {
+        player_id: PLAYER_ID,
+        session_token: 0,
+    });
+
+    // The player joins last, so they get to go first once the game begins
+    let events = [
+        GameEvent::PlayerJoined {
+            player_id: COMPUTER_ID,
+            name: format!("Computer ({:?})", computer.difficulty),
+        },
+        GameEvent::PlayerJoined {
+            player_id: PLAYER_ID,
+            name: user_data.name.clone(),
+        },
+    ];
+    for event in events {
+        play(event, &mut game_state, &mut game_events)
+            .expect("A new game has room for two players");
+    }
+}
+
+fn play_local_game(
+    mut actions: EventReader<PlayerAction>,
+    mut game_state: ResMut<GameState>,
+    mut game_events: EventWriter<GameEvent>,
+    mut notices: EventWriter<ShowNotice>,
+    mut computer: ResMut<Computer>,
+    time: Res<Time>,
+) {
+    for PlayerAction(message) in actions.iter() {
+        let event = match message.clone().into_event(PLAYER_ID) {
+            Some(event) => event,
+            None => continue,
+        };
+        if let Err(reason) = play(event.clone(), &mut game_state, &mut game_events) {
+            notices.send(ShowNotice(reason.to_string()));
+            continue;
+        }
+
+        // The computer is always up for another game
+        if matches!(event, GameEvent::RequestRematch { .. }) {
+            let event = GameEvent::AcceptRematch {
+                player_id: COMPUTER_ID,
+            };
+            play(event, &mut game_state, &mut game_events)
+                .expect("The computer can always accept a rematch the player asked for");
+        }
+
+        // The computer is a good sport and lets the player take back their moves
+        if matches!(event, GameEvent::RequestTakeback { .. }) {
+            let event = GameEvent::AcceptTakeback {
+                player_id: COMPUTER_ID,
+            };
+            play(event, &mut game_state, &mut game_events)
+                .expect("The computer can always accept a takeback the player asked for");
+        }
+    }
+
+    if game_state.stage != Stage::InGame || game_state.active_player_id != COMPUTER_ID {
+        computer.thinking.reset();
+        return;
+    }
+
+    if computer.thinking.tick(time.delta()).finished() {
+        if let Some(at) = ai::choose_move(&game_state, COMPUTER_ID, computer.difficulty) {
+            let event = GameEvent::PlaceTile {
+                player_id: COMPUTER_ID,
+                at,
+            };
+            play(event, &mut game_state, &mut game_events)
+                .expect("The computer only picks empty tiles on its own turn");
+        }
+    }
+}
+
+/// Plays an event along with everything that follows from it, like the game ending,
+/// and tells the rest of the client about it, just like the server would.
+fn play(
+    event: GameEvent,
+    game_state: &mut GameState,
+    game_events: &mut EventWriter<GameEvent>,
+) -> Result<(), ValidationError> {
+    for event in game_state.apply(event)? {
+        game_events.send(event);
+    }
+    Ok(())
+}
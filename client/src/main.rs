@@ -1,58 +1,243 @@
 use bevy::prelude::*;
+use bevy::tasks::{IoTaskPool, Task};
 use bevy_renet::{run_if_client_connected, RenetClientPlugin};
-use renet::{
-    ClientAuthentication, RenetClient, RenetConnectionConfig, RenetError, NETCODE_USER_DATA_BYTES,
+use chat::{ChatPlugin, CHAT_PANEL_WIDTH};
+use clap::Parser;
+use futures_lite::future;
+use local::LocalGamePlugin;
+use renet::{RenetClient, RenetError};
+use replay::ReplayPlugin;
+use std::path::PathBuf;
+use std::time::Duration;
+use store::ai::Difficulty;
+use store::config::{read_config, ClientConfig};
+use store::net::{self, ClientSession, ConnectionRefused, Session, GAME_CHANNEL};
+use store::replay::Replay;
+use store::{
+    ClientMessage, EndGameReason, GameEvent, GameState, LeaderboardEntry, PlayerId, Rules,
+    ServerMessage, Stage, TilePosition, UserData, Variant, SUB_BOARD_SIZE,
 };
-use std::{net::UdpSocket, time::SystemTime};
-use store::{EndGameReason, GameEvent, GameState};
-
-// This id needs to be the same that the server is using
-const PROTOCOL_ID: u64 = 1208;
-
-fn main() {
-    // Get username from stdin args
-    let args = std::env::args().collect::<Vec<String>>();
-    let username = &args[1];
-
-    App::new()
-        .insert_resource(WindowDescriptor {
-            title: format!("TicTacTussle <{}>", username),
-            width: 480.0,
-            height: 540.0,
-            ..default()
-        })
-        .insert_resource(ClearColor(Color::hex("282828").unwrap()))
-        .add_plugins(DefaultPlugins)
+
+mod chat;
+mod local;
+mod replay;
+
+// The board is drawn within a square of this many pixels, no matter how many tiles it has
+const BOARD_SIZE: f32 = 480.0;
+// The board is pushed down a bit to make room for the ui at the top of the window
+const BOARD_OFFSET_Y: f32 = -30.0;
+
+/// What the client was started to do
+enum Mode {
+    /// Play against other players through the server
+    Online,
+    /// Play against the computer, no server needed
+    Local(Difficulty),
+    /// Watch a recorded match
+    Replay(Replay),
+}
+
+/// Play TicTacTussle online, against the computer or watch a replay.
+/// The host, port and name can also be set in a config file. Anything passed on the command line wins.
+#[derive(Debug, Parser)]
+#[command(name = "client")]
+struct Args {
+    /// The name to play as
+    name: Option<String>,
+    /// The room to join. Without one you are paired up with whoever else is waiting for an opponent
+    room: Option<String>,
+    /// Play against the computer instead, at the given difficulty (easy, medium or hard)
+    #[arg(
+        long,
+        value_name = "DIFFICULTY",
+        num_args = 0..=1,
+        default_missing_value = "medium",
+        conflicts_with = "room"
+    )]
+    ai: Option<Difficulty>,
+    /// Play ultimate tic-tac-toe against the computer
+    #[arg(long, requires = "ai")]
+    ultimate: bool,
+    /// Watch the match recorded in a replay file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["name", "room", "ai"])]
+    replay: Option<PathBuf>,
+    /// A toml file with the host, port and name to use
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// The host the server is running on
+    #[arg(long)]
+    host: Option<String>,
+    /// The port the server is listening on
+    #[arg(long)]
+    port: Option<u16>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let config: ClientConfig = read_config(args.config.as_deref())?;
+    let config = ClientConfig {
+        host: args.host.unwrap_or(config.host),
+        port: args.port.unwrap_or(config.port),
+        name: args.name.or(config.name),
+    };
+    let mode = match (args.replay, args.ai) {
+        (Some(path), _) => Mode::Replay(Replay::load(&path)?),
+        (None, Some(difficulty)) => Mode::Local(difficulty),
+        (None, None) => Mode::Online,
+    };
+    let name = match (&mode, config.name.clone()) {
+        (_, Some(name)) => name,
+        // Nobody is playing in a replay, so it doesn't need a name
+        (Mode::Replay(_), None) => String::new(),
+        (_, None) => anyhow::bail!(
+            "No name to play as, pass one as the first argument or set name in the config file"
+        ),
+    };
+    let user_data = UserData {
+        name,
+        room: match mode {
+            Mode::Online => args.room,
+            _ => None,
+        },
+        session_token: None,
+    };
+
+    let mut app = App::new();
+    app.insert_resource(WindowDescriptor {
+        title: match (&mode, &user_data.room) {
+            (Mode::Replay(replay), _) => {
+                let names: Vec<&str> = replay.players.values().map(String::as_str).collect();
+                format!("TicTacTussle replay of {}", names.join(" vs "))
+            }
+            (Mode::Local(_), _) => format!("TicTacTussle <{}> vs the computer", user_data.name),
+            (Mode::Online, Some(room)) => format!("TicTacTussle <{}> in {}", user_data.name, room),
+            (Mode::Online, None) => format!("TicTacTussle <{}>", user_data.name),
+        },
+        // Online games have a chat panel next to the board
+        width: match mode {
+            Mode::Online => 480.0 + CHAT_PANEL_WIDTH,
+            _ => 480.0,
+        },
+        height: 540.0,
+        ..default()
+    })
+    .insert_resource(ClearColor(Color::hex("282828").unwrap()))
+    .add_plugins(DefaultPlugins)
+    // Add our game state and register GameEvent as a bevy event
+    .insert_resource(GameState::default())
+    .add_event::<GameEvent>()
+    .add_event::<PlayerAction>()
+    .add_event::<StateSynced>()
+    .add_event::<ShowNotice>()
+    // Add setup function to spawn UI and board graphics
+    .add_startup_system(setup)
+    // Add systems for playing TicTacTussle
+    .add_system(change_ui_by_stage)
+    .add_system(update_waiting_text)
+    .add_system(update_leaderboard)
+    .add_system(update_in_game_ui)
+    .add_system(update_clocks)
+    .add_system(spawn_board)
+    .add_system(update_board.after(spawn_board))
+    .add_system(update_sub_board_highlight.after(spawn_board))
+    .add_system(input)
+    .add_system(show_notices)
+    .add_system(notify_connection_changes)
+    .add_system(hide_notice)
+    .add_system(rematch_button)
+    .add_system(update_rematch_label)
+    .add_system(takeback_button)
+    .add_system(decline_takeback_button)
+    .add_system(update_takeback_buttons)
+    .add_system(draw_button)
+    .add_system(update_draw_label)
+    .add_system(resign_button)
+    .add_system(notify_offers);
+
+    match mode {
+        Mode::Replay(replay) => {
+            app.add_plugin(ReplayPlugin { replay });
+        }
+        Mode::Local(difficulty) => {
+            let rules = if args.ultimate {
+                Rules::ultimate()
+            } else {
+                Rules::default()
+            };
+            app.add_plugin(LocalGamePlugin { difficulty, rules })
+                .insert_resource(user_data);
+        }
         // Renet setup
-        .add_plugin(RenetClientPlugin)
-        .insert_resource(new_renet_client(&username).unwrap())
-        .add_system(handle_renet_error)
-        .add_system_to_stage(
-            CoreStage::PostUpdate,
-            receive_events_from_server.with_run_criteria(run_if_client_connected),
-        )
-        // Add our game state and register GameEvent as a bevy event
-        .insert_resource(GameState::default())
-        .add_event::<GameEvent>()
-        // Add setup function to spawn UI and board graphics
-        .add_startup_system(setup)
-        // Add systems for playing TicTacTussle
-        .add_system(change_ui_by_stage)
-        .add_system(update_waiting_text)
-        .add_system(update_in_game_ui)
-        .add_system(update_board)
-        .add_system(input)
-        // Finally we run the thing!
-        .run();
+        Mode::Online => {
+            let connection = ClientSession::new(config.server_addr()?, user_data);
+            let client = match connection.connect() {
+                Ok(client) => client,
+                // Tell the player why they can't play instead of closing the window on them
+                Err(err) => {
+                    let refused = err.downcast::<ConnectionRefused>()?;
+                    app.insert_resource(refused).run();
+                    return Ok(());
+                }
+            };
+            app.add_plugin(RenetClientPlugin)
+                .add_plugin(ChatPlugin)
+                .insert_resource(client)
+                .insert_resource(connection)
+                .add_system(handle_renet_error)
+                .add_system(finish_reconnecting)
+                .add_system(send_actions_to_server.with_run_criteria(run_if_client_connected))
+                .add_system_to_stage(
+                    CoreStage::PostUpdate,
+                    receive_events_from_server.with_run_criteria(run_if_client_connected),
+                );
+        }
+    }
+
+    // Finally we run the thing!
+    app.run();
+    Ok(())
 }
 
+////////// RESOURCES //////////
+/// The best rated players on the server, shown while waiting for an opponent
+struct Leaderboard(Vec<LeaderboardEntry>);
+
+/// Fetching a connect token waits on the server, so reconnecting happens off the main thread
+struct Reconnecting(Task<anyhow::Result<RenetClient>>);
+
+/// Present when we are only watching the game, either from a full room or in a replay
+struct Spectating;
+
+////////// EVENTS //////////
+/// Sent when the whole game state has been replaced by a snapshot from the server,
+/// like when joining a game, reconnecting or recovering from a desync
+struct StateSynced;
+
+/// Sent to show the player a short message at the bottom of the screen
+struct ShowNotice(String);
+
+/// Sent when the player wants something to happen in the game, like placing a tile.
+/// It is either sent to the server or played out locally, depending on who we are playing against
+struct PlayerAction(ClientMessage);
+
 ////////// COMPONENTS //////////
 #[derive(Component)]
 struct UIRoot;
 
-type TileIndex = usize;
 #[derive(Component)]
-struct HoverDot(pub TileIndex);
+struct HoverDot(pub TilePosition);
+
+/// Marks everything that makes up the board, so it can be despawned when the rules change
+#[derive(Component)]
+struct BoardEntity;
+
+/// A tile placed on the board by one of the players
+#[derive(Component)]
+struct Piece;
+
+/// Marks the small board the active player has to play in, in ultimate tic-tac-toe
+#[derive(Component)]
+struct SubBoardHighlight;
 
 #[derive(Component)]
 struct WaitingText;
@@ -60,43 +245,87 @@ struct WaitingText;
 #[derive(Component)]
 struct PlayerHandle(pub u64);
 
-////////// SETUP //////////
-fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
-    commands.spawn_bundle(Camera2dBundle::default());
+#[derive(Component)]
+struct RematchButton;
 
-    // Spawn board background
-    commands.spawn_bundle(SpriteBundle {
-        transform: Transform::from_xyz(0.0, -30.0, 0.0),
-        sprite: Sprite {
-            custom_size: Some(Vec2::new(480.0, 480.0)),
-            ..default()
-        },
-        texture: asset_server.load("background.png").into(),
-        ..default()
-    });
+#[derive(Component)]
+struct RematchLabel;
 
-    // Spawn a dot in each tile for hover effect
-    for x in 0..3 {
-        for y in 0..3 {
-            commands
-                .spawn_bundle(SpriteBundle {
-                    transform: Transform::from_xyz(
-                        160.0 * (x as f32 - 1.0),
-                        -30.0 + 160.0 * (y as f32 - 1.0),
-                        0.0,
-                    ),
-                    sprite: Sprite {
-                        color: Color::rgba(1.0, 1.0, 1.0, 0.0),
-                        custom_size: Some(Vec2::new(160.0, 160.0)),
-                        ..default()
-                    },
-                    texture: asset_server.load("dot.png").into(),
-                    ..default()
-                })
-                .insert(HoverDot(x + y * 3));
+#[derive(Component)]
+struct TakebackButton;
+
+#[derive(Component)]
+struct TakebackLabel;
+
+/// Only shown while the opponent is waiting for an answer to their takeback
+#[derive(Component)]
+struct DeclineTakebackButton;
+
+#[derive(Component)]
+struct DrawButton;
+
+#[derive(Component)]
+struct DrawLabel;
+
+#[derive(Component)]
+struct ResignButton;
+
+#[derive(Component)]
+struct LeaderboardPanel;
+
+#[derive(Component)]
+struct LeaderboardText;
+
+/// Short lived text telling the player something, like why their move was rejected
+#[derive(Component)]
+struct Notice(pub Timer);
+
+////////// BOARD LAYOUT //////////
+/// Describes where the tiles of a board are placed on screen
+struct BoardLayout {
+    rules: Rules,
+    tile_size: f32,
+    // The world position of the bottom left corner of the board
+    origin: Vec2,
+}
+
+impl BoardLayout {
+    fn new(rules: Rules) -> Self {
+        // Scale tiles so the longest side of the board fills the board area
+        let tile_size = BOARD_SIZE / rules.width.max(rules.height) as f32;
+        let size = Vec2::new(rules.width as f32, rules.height as f32) * tile_size;
+        Self {
+            rules,
+            tile_size,
+            origin: Vec2::new(0.0, BOARD_OFFSET_Y) - size / 2.0,
         }
     }
 
+    /// The world position of the center of a tile
+    fn tile_center(&self, at: TilePosition) -> Vec3 {
+        let (x, y) = self.rules.position_coordinates(at);
+        let center = self.origin + (Vec2::new(x as f32, y as f32) + 0.5) * self.tile_size;
+        center.extend(0.0)
+    }
+
+    /// The world position of the center of one of the small boards of ultimate tic-tac-toe
+    fn sub_board_center(&self, sub_board: usize) -> Vec3 {
+        // The middle tile of a small board sits right in the center of it
+        let cell = SUB_BOARD_SIZE * SUB_BOARD_SIZE / 2;
+        self.tile_center(TilePosition::SubBoard { sub_board, cell })
+    }
+
+    /// The tile at a world position, if the position is on the board
+    fn tile_at(&self, position: Vec2) -> Option<TilePosition> {
+        let tile = ((position - self.origin) / self.tile_size).floor();
+        self.rules.position(tile.x as isize, tile.y as isize)
+    }
+}
+
+////////// SETUP //////////
+fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn_bundle(Camera2dBundle::default());
+
     // Spawn pregame ui
     commands
         // A container that centers its children on the screen
@@ -129,6 +358,61 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
                 ))
                 .insert(WaitingText);
         });
+
+    // Spawn notice text at the bottom of the screen. It is empty until there is something to say
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load("Inconsolata.ttf"),
+                    font_size: 20.0,
+                    color: Color::hex("fb4934").unwrap(),
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(16.0),
+                    bottom: Val::Px(16.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(Notice(Timer::from_seconds(3.0, false)));
+
+    // Spawn the leaderboard in the middle of the screen. It is hidden until the server has sent it to us
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(48.0),
+                    right: Val::Px(48.0),
+                    top: Val::Px(96.0),
+                    ..default()
+                },
+                padding: UiRect::all(Val::Px(16.0)),
+                ..default()
+            },
+            color: Color::hex("3c3836").unwrap().into(),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert(LeaderboardPanel)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font: asset_server.load("Inconsolata.ttf"),
+                        font_size: 20.0,
+                        color: Color::hex("ebdbb2").unwrap(),
+                    },
+                ))
+                .insert(LeaderboardText);
+        });
 }
 
 ////////// UPDATE SYSTEMS //////////
@@ -137,24 +421,29 @@ fn input(
     input: Res<Input<MouseButton>>,
     game_state: Res<GameState>,
     mut hover_dots: Query<(&HoverDot, &mut Sprite)>,
-    mut client: ResMut<RenetClient>,
+    mut actions: EventWriter<PlayerAction>,
+    session: Option<Res<Session>>,
+    camera: Query<&Transform, With<Camera>>,
 ) {
-    // We only want to handle inputs once we are ingame
-    if game_state.stage != store::Stage::InGame {
+    // We only want to handle inputs once we are ingame, and only if we are playing rather than spectating
+    if session.is_none() || game_state.stage != Stage::InGame {
         return;
     }
 
     let window = windows.get_primary().unwrap();
     if let Some(mouse_position) = window.cursor_position() {
-        // Determine the index of the tile that the mouse is currently over
-        let x_tile: usize = (mouse_position.x / 160.0).floor() as usize;
-        let y_tile: usize = (mouse_position.y / 160.0).floor() as usize;
-        let tile = x_tile + y_tile * 3;
+        // Determine the index of the tile that the mouse is currently over.
+        // The cursor position is measured from the bottom left corner of the window,
+        // while the board is positioned relative to the camera, which looks at the center of the window.
+        let window_size = Vec2::new(window.width(), window.height());
+        let camera_position = camera.single().translation.truncate();
+        let layout = BoardLayout::new(game_state.rules);
 
         // If mouse is outside of board we do nothing
-        if 8 < tile {
-            return;
-        }
+        let tile = match layout.tile_at(mouse_position - window_size / 2.0 + camera_position) {
+            Some(tile) => tile,
+            None => return,
+        };
 
         // Toggle hover dots on and off
         for (dot, mut dot_sprite) in hover_dots.iter_mut() {
@@ -165,56 +454,271 @@ fn input(
             }
         }
 
-        // If left mouse button is pressed, send a place tile event to the server
+        // If left mouse button is pressed, try to place a tile
         if input.just_pressed(MouseButton::Left) {
-            let event = GameEvent::PlaceTile {
-                player_id: client.client_id(),
-                at: tile,
-            };
-            client.send_message(0, bincode::serialize(&event).unwrap());
+            actions.send(PlayerAction(ClientMessage::PlaceTile { at: tile }));
         }
     }
 }
 
-fn update_board(
+fn spawn_board(
     mut commands: Commands,
     game_state: Res<GameState>,
-    mut game_events: EventReader<GameEvent>,
+    board_entities: Query<Entity, With<BoardEntity>>,
+    mut spawned_rules: Local<Option<Rules>>,
     asset_server: Res<AssetServer>,
 ) {
-    for event in game_events.iter() {
-        match event {
-            GameEvent::PlaceTile { player_id, at } => {
-                let x = at % 3;
-                let y = at / 3;
-                let texture =
-                    asset_server.load(match game_state.get_player_tile(player_id).unwrap() {
-                        store::Tile::Tac => "tac.png",
-                        store::Tile::Tic => "tic.png",
-                        store::Tile::Empty => "dot.png", // This should never happen
-                    });
+    // The board only needs to be rebuilt when it has a new shape
+    if *spawned_rules == Some(game_state.rules) {
+        return;
+    }
+    *spawned_rules = Some(game_state.rules);
+
+    for entity in board_entities.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let layout = BoardLayout::new(game_state.rules);
+    let (width, height) = (game_state.rules.width, game_state.rules.height);
+    let size = Vec2::new(width as f32, height as f32) * layout.tile_size;
+    let line_color = Color::hex("665c54").unwrap();
+    let line_thickness = (layout.tile_size / 16.0).clamp(1.0, 6.0);
+    // Lines stop a bit before the edge of the board, so it looks a bit hand drawn
+    let inset = layout.tile_size / 10.0;
+    // The lines between the small boards of ultimate tic-tac-toe stand out from the lines within them
+    let is_ultimate = game_state.rules.variant == Variant::Ultimate;
+    let line_style = |at: usize| {
+        if is_ultimate && at % SUB_BOARD_SIZE == 0 {
+            (Color::hex("a89984").unwrap(), line_thickness * 2.0)
+        } else {
+            (line_color, line_thickness)
+        }
+    };
 
-                commands.spawn_bundle(SpriteBundle {
-                    transform: Transform::from_xyz(
-                        160.0 * (x as f32 - 1.0),
-                        -30.0 + 160.0 * (y as f32 - 1.0),
-                        0.0,
+    // The classic board has a hand drawn background, other boards are drawn with lines
+    if !is_ultimate && (width, height) == (3, 3) {
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform::from_translation((layout.origin + size / 2.0).extend(0.0)),
+                sprite: Sprite {
+                    custom_size: Some(size),
+                    ..default()
+                },
+                texture: asset_server.load("background.png").into(),
+                ..default()
+            })
+            .insert(BoardEntity);
+    } else {
+        // Spawn the lines between columns
+        for x in 1..width {
+            let (color, thickness) = line_style(x);
+            commands
+                .spawn_bundle(SpriteBundle {
+                    transform: Transform::from_translation(
+                        (layout.origin + Vec2::new(x as f32 * layout.tile_size, size.y / 2.0))
+                            .extend(0.0),
                     ),
                     sprite: Sprite {
-                        custom_size: Some(Vec2::new(160.0, 160.0)),
+                        color,
+                        custom_size: Some(Vec2::new(thickness, size.y - 2.0 * inset)),
                         ..default()
                     },
-                    texture: texture.into(),
                     ..default()
-                });
+                })
+                .insert(BoardEntity);
+        }
+
+        // Spawn the lines between rows
+        for y in 1..height {
+            let (color, thickness) = line_style(y);
+            commands
+                .spawn_bundle(SpriteBundle {
+                    transform: Transform::from_translation(
+                        (layout.origin + Vec2::new(size.x / 2.0, y as f32 * layout.tile_size))
+                            .extend(0.0),
+                    ),
+                    sprite: Sprite {
+                        color,
+                        custom_size: Some(Vec2::new(size.x - 2.0 * inset, thickness)),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(BoardEntity);
+        }
+    }
+
+    // Spawn the highlight behind the small board the active player is sent to.
+    // It is moved into place by update_sub_board_highlight
+    if is_ultimate {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::hex("3c3836").unwrap(),
+                    custom_size: Some(Vec2::splat(SUB_BOARD_SIZE as f32 * layout.tile_size)),
+                    ..default()
+                },
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(SubBoardHighlight)
+            .insert(BoardEntity);
+    }
+
+    // Spawn a dot in each tile for hover effect
+    for at in game_state.rules.positions() {
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform::from_translation(layout.tile_center(at)),
+                sprite: Sprite {
+                    color: Color::rgba(1.0, 1.0, 1.0, 0.0),
+                    custom_size: Some(Vec2::splat(layout.tile_size)),
+                    ..default()
+                },
+                texture: asset_server.load("dot.png").into(),
+                ..default()
+            })
+            .insert(HoverDot(at))
+            .insert(BoardEntity);
+    }
+}
+
+fn show_notices(mut notices: EventReader<ShowNotice>, mut notice: Query<(&mut Text, &mut Notice)>) {
+    for ShowNotice(message) in notices.iter() {
+        let (mut text, mut notice) = notice.single_mut();
+        text.sections[0].value = message.clone();
+        notice.0.reset();
+    }
+}
+
+fn notify_connection_changes(
+    game_state: Res<GameState>,
+    session: Option<Res<Session>>,
+    mut game_events: EventReader<GameEvent>,
+    mut notices: EventWriter<ShowNotice>,
+) {
+    for event in game_events.iter() {
+        let (player_id, message) = match event {
+            GameEvent::PlayerConnectionLost { player_id } => (
+                player_id,
+                "lost their connection, waiting for them to return...",
+            ),
+            GameEvent::PlayerReconnected { player_id } => (player_id, "is back!"),
+            _ => continue,
+        };
+
+        // We know when we lose our own connection, no need to tell us
+        let is_us = session.as_ref().map(|session| session.player_id) == Some(*player_id);
+        if let (false, Some(player)) = (is_us, game_state.players.get(player_id)) {
+            notices.send(ShowNotice(format!("{} {}", player.name, message)));
+        }
+    }
+}
+
+fn hide_notice(time: Res<Time>, mut notice: Query<(&mut Text, &mut Notice)>) {
+    let (mut text, mut notice) = notice.single_mut();
+    if notice.0.tick(time.delta()).just_finished() {
+        text.sections[0].value.clear();
+    }
+}
+
+fn update_board(
+    mut commands: Commands,
+    game_state: Res<GameState>,
+    pieces: Query<Entity, With<Piece>>,
+    asset_server: Res<AssetServer>,
+) {
+    // Redraw every piece whenever the game state changes. Boards are small, so this is cheap,
+    // and it keeps the board right however the state came to be, like when catching up after a reconnect.
+    if !game_state.is_changed() {
+        return;
+    }
+
+    for piece in pieces.iter() {
+        commands.entity(piece).despawn();
+    }
+
+    let layout = BoardLayout::new(game_state.rules);
+    for at in game_state.rules.positions() {
+        let texture = asset_server.load(match game_state.tile(at).unwrap() {
+            store::Tile::Tac => "tac.png",
+            store::Tile::Tic => "tic.png",
+            store::Tile::Empty => continue,
+        });
+
+        commands
+            .spawn_bundle(SpriteBundle {
+                transform: Transform::from_translation(layout.tile_center(at)),
+                sprite: Sprite {
+                    custom_size: Some(Vec2::splat(layout.tile_size)),
+                    ..default()
+                },
+                texture: texture.into(),
+                ..default()
+            })
+            .insert(Piece);
+    }
+
+    // Claimed small boards of ultimate tic-tac-toe are covered by a big piece of whoever claimed them
+    for (sub_board, board) in game_state.sub_boards.iter().enumerate() {
+        let texture = asset_server.load(match board.winner {
+            store::Tile::Tac => "tac.png",
+            store::Tile::Tic => "tic.png",
+            store::Tile::Empty => continue,
+        });
+
+        commands
+            .spawn_bundle(SpriteBundle {
+                // In front of the small pieces, which still show through
+                transform: Transform::from_translation(
+                    layout.sub_board_center(sub_board) + Vec3::Z,
+                ),
+                sprite: Sprite {
+                    color: Color::rgba(1.0, 1.0, 1.0, 0.75),
+                    custom_size: Some(Vec2::splat(SUB_BOARD_SIZE as f32 * layout.tile_size)),
+                    ..default()
+                },
+                texture: texture.into(),
+                ..default()
+            })
+            .insert(Piece);
+    }
+}
+
+fn update_sub_board_highlight(
+    game_state: Res<GameState>,
+    spawned: Query<(), Added<SubBoardHighlight>>,
+    mut highlights: Query<(&mut Transform, &mut Visibility), With<SubBoardHighlight>>,
+) {
+    if !game_state.is_changed() && spawned.is_empty() {
+        return;
+    }
+
+    let layout = BoardLayout::new(game_state.rules);
+    for (mut transform, mut visibility) in highlights.iter_mut() {
+        match game_state.forced_sub_board {
+            Some(sub_board) if game_state.stage == Stage::InGame => {
+                // Behind the lines and pieces of the board
+                transform.translation = layout.sub_board_center(sub_board) - Vec3::Z;
+                visibility.is_visible = true;
             }
-            _ => {}
+            _ => visibility.is_visible = false,
         }
     }
 }
 
-fn update_waiting_text(mut text_query: Query<&mut Text, With<WaitingText>>, time: Res<Time>) {
+fn update_waiting_text(
+    mut text_query: Query<&mut Text, With<WaitingText>>,
+    refused: Option<Res<ConnectionRefused>>,
+    time: Res<Time>,
+) {
     if let Ok(mut text) = text_query.get_single_mut() {
+        // There is no opponent coming if the server won't let us in
+        if let Some(refused) = refused {
+            text.sections[0].value = refused.0.clone();
+            return;
+        }
+
         let num_dots = (time.time_since_startup().as_secs() % 3) + 1;
         text.sections[0].value = format!(
             "Waiting for an opponent{}{}",
@@ -225,86 +729,129 @@ fn update_waiting_text(mut text_query: Query<&mut Text, With<WaitingText>>, time
     }
 }
 
+fn update_leaderboard(
+    game_state: Res<GameState>,
+    leaderboard: Option<Res<Leaderboard>>,
+    mut panel: Query<&mut Visibility, With<LeaderboardPanel>>,
+    mut text: Query<&mut Text, With<LeaderboardText>>,
+) {
+    let leaderboard = match leaderboard {
+        Some(leaderboard) if leaderboard.is_changed() || game_state.is_changed() => leaderboard,
+        _ => return,
+    };
+
+    // The leaderboard is something to look at while waiting, it would just be in the way during a game
+    panel.single_mut().is_visible = game_state.stage == Stage::PreGame;
+
+    let mut lines = vec!["Leaderboard".to_string(), String::new()];
+    for (rank, entry) in leaderboard.0.iter().enumerate() {
+        lines.push(format!(
+            "{:>2}. {:<14} {:>4}  {}/{}/{}",
+            rank + 1,
+            entry.name,
+            entry.rating,
+            entry.wins,
+            entry.losses,
+            entry.draws
+        ));
+    }
+    if leaderboard.0.is_empty() {
+        lines.push("Nobody has played yet".to_string());
+    }
+    text.single_mut().sections[0].value = lines.join("\n");
+}
+
 fn change_ui_by_stage(
     mut commands: Commands,
     game_state: Res<GameState>,
     mut game_events: EventReader<GameEvent>,
+    mut state_synced: EventReader<StateSynced>,
     mut ui_root: Query<(Entity, &mut Style), With<UIRoot>>,
+    spectating: Option<Res<Spectating>>,
     asset_server: Res<AssetServer>,
 ) {
     let (ui_root_entity, mut ui_root_style) = ui_root.get_single_mut().unwrap();
     let mut ui_root = commands.entity(ui_root_entity);
+    let spectating = spectating.is_some();
+    // Players see their names in opposite corners, spectators get a header in the middle
+    let in_game_justify_content = if spectating {
+        JustifyContent::Center
+    } else {
+        JustifyContent::SpaceBetween
+    };
+
+    // A snapshot can put us anywhere in a game, so rebuild the ui for whatever stage it is in
+    if state_synced.iter().last().is_some() {
+        ui_root.despawn_descendants();
+        match game_state.stage {
+            Stage::PreGame => {
+                ui_root_style.justify_content = JustifyContent::Center;
+                ui_root.with_children(|parent| spawn_waiting_ui(parent, &asset_server));
+            }
+            Stage::InGame => {
+                ui_root_style.justify_content = in_game_justify_content;
+                ui_root.with_children(|parent| {
+                    spawn_in_game_ui(parent, &game_state, spectating, &asset_server)
+                });
+            }
+            Stage::Ended => {
+                ui_root_style.justify_content = JustifyContent::Center;
+                // The reason the game ended is found in the last EndGame event that was played
+                let reason = game_state
+                    .history
+                    .iter()
+                    .rev()
+                    .find_map(|event| match event {
+                        GameEvent::EndGame { reason } => Some(reason),
+                        _ => None,
+                    });
+                if let Some(reason) = reason {
+                    ui_root.with_children(|parent| {
+                        spawn_end_game_ui(parent, &game_state, reason, spectating, &asset_server)
+                    });
+                }
+            }
+        }
+    }
 
     for event in game_events.iter() {
         match event {
-            GameEvent::BeginGame { goes_first: _ } => {
-                // Remove waiting text when game begins
+            GameEvent::BeginGame { goes_first: _ } | GameEvent::AcceptRematch { player_id: _ } => {
+                // Remove waiting text or end game ui when game begins
                 ui_root.despawn_descendants();
 
                 // Spawn in game ui
-                ui_root_style.justify_content = JustifyContent::SpaceBetween;
+                ui_root_style.justify_content = in_game_justify_content;
                 ui_root.with_children(|parent| {
-                    for (player_id, player) in game_state.players.iter() {
-                        let is_active_player = game_state.active_player_id == *player_id;
-                        let is_tac_player = player.piece == store::Tile::Tac;
-
-                        parent
-                            .spawn_bundle(TextBundle::from_section(
-                                player.name.clone(),
-                                TextStyle {
-                                    font: asset_server.load("Inconsolata.ttf"),
-                                    font_size: 24.0,
-                                    color: if !is_active_player {
-                                        Color::hex("ebdbb2").unwrap()
-                                    } else {
-                                        if is_tac_player {
-                                            Color::hex("d65d0e").unwrap()
-                                        } else {
-                                            Color::hex("458488").unwrap()
-                                        }
-                                    },
-                                },
-                            ))
-                            .insert(PlayerHandle(*player_id));
-                    }
+                    spawn_in_game_ui(parent, &game_state, spectating, &asset_server)
                 });
             }
             GameEvent::EndGame { reason } => {
                 // Despawn in game ui
                 ui_root.despawn_descendants();
                 ui_root_style.justify_content = JustifyContent::Center;
-                match reason {
-                    EndGameReason::PlayerLeft { player_id: _ } => {
-                        ui_root.with_children(|parent| {
-                            parent.spawn_bundle(TextBundle::from_section(
-                                "Your opponent has left",
-                                TextStyle {
-                                    font: asset_server.load("Inconsolata.ttf"),
-                                    font_size: 24.0,
-                                    color: Color::hex("ebdbb2").unwrap(),
-                                },
-                            ));
-                        });
-                    }
-                    EndGameReason::PlayerWon { winner } => {
-                        ui_root.with_children(|parent| {
-                            let winner_player = game_state.players.get(winner).unwrap();
-                            let is_tac_player = winner_player.piece == store::Tile::Tac;
-
-                            parent.spawn_bundle(TextBundle::from_section(
-                                format!("{} has won!", winner_player.name.clone()),
-                                TextStyle {
-                                    font: asset_server.load("Inconsolata.ttf"),
-                                    font_size: 24.0,
-                                    color: if is_tac_player {
-                                        Color::hex("d65d0e").unwrap()
-                                    } else {
-                                        Color::hex("458488").unwrap()
-                                    },
-                                },
-                            ));
-                        });
-                    }
+                ui_root.with_children(|parent| {
+                    spawn_end_game_ui(parent, &game_state, reason, spectating, &asset_server)
+                });
+            }
+            GameEvent::PlayerDisconnected { player_id: _ } => {
+                // There is nobody left to play again with once the game has ended
+                if game_state.stage == Stage::Ended {
+                    ui_root.despawn_descendants();
+                    ui_root.with_children(|parent| {
+                        parent.spawn_bundle(TextBundle::from_section(
+                            if spectating {
+                                "A player has left"
+                            } else {
+                                "Your opponent has left"
+                            },
+                            TextStyle {
+                                font: asset_server.load("Inconsolata.ttf"),
+                                font_size: 24.0,
+                                color: Color::hex("ebdbb2").unwrap(),
+                            },
+                        ));
+                    });
                 }
             }
             _ => {}
@@ -312,6 +859,454 @@ fn change_ui_by_stage(
     }
 }
 
+fn spawn_waiting_ui(parent: &mut ChildBuilder, asset_server: &AssetServer) {
+    parent
+        .spawn_bundle(TextBundle::from_section(
+            "Waiting for an opponent...",
+            TextStyle {
+                font: asset_server.load("Inconsolata.ttf"),
+                font_size: 24.0,
+                color: Color::hex("ebdbb2").unwrap(),
+            },
+        ))
+        .insert(WaitingText);
+}
+
+/// Spawns the names of the players, highlighting the one whose turn it is.
+/// Spectators get them in a "Spectating X vs Y" header.
+fn spawn_in_game_ui(
+    parent: &mut ChildBuilder,
+    game_state: &GameState,
+    spectating: bool,
+    asset_server: &AssetServer,
+) {
+    let text_style = TextStyle {
+        font: asset_server.load("Inconsolata.ttf"),
+        font_size: 24.0,
+        color: Color::hex("ebdbb2").unwrap(),
+    };
+    if spectating {
+        parent.spawn_bundle(TextBundle::from_section("Spectating ", text_style.clone()));
+    }
+
+    for (i, (player_id, player)) in game_state.players.iter().enumerate() {
+        if spectating && i > 0 {
+            parent.spawn_bundle(TextBundle::from_section(" vs ", text_style.clone()));
+        }
+        // Players get to ask for takebacks, offer draws and resign from between their names
+        if !spectating && i > 0 {
+            spawn_game_buttons(parent, asset_server);
+        }
+
+        let is_active_player = game_state.active_player_id == *player_id;
+        let is_tac_player = player.piece == store::Tile::Tac;
+
+        parent
+            .spawn_bundle(TextBundle::from_sections([
+                TextSection::new(
+                    player.name.clone(),
+                    TextStyle {
+                        font: asset_server.load("Inconsolata.ttf"),
+                        font_size: 24.0,
+                        color: if !is_active_player {
+                            Color::hex("ebdbb2").unwrap()
+                        } else {
+                            if is_tac_player {
+                                Color::hex("d65d0e").unwrap()
+                            } else {
+                                Color::hex("458488").unwrap()
+                            }
+                        },
+                    },
+                ),
+                // The clock of the player, filled in by update_clocks when the game has time limits
+                TextSection::new(
+                    "",
+                    TextStyle {
+                        font: asset_server.load("Inconsolata.ttf"),
+                        font_size: 24.0,
+                        color: Color::hex("928374").unwrap(),
+                    },
+                ),
+            ]))
+            .insert(PlayerHandle(*player_id));
+    }
+}
+
+fn spawn_end_game_ui(
+    parent: &mut ChildBuilder,
+    game_state: &GameState,
+    reason: &EndGameReason,
+    spectating: bool,
+    asset_server: &AssetServer,
+) {
+    match reason {
+        EndGameReason::PlayerLeft { player_id: _ } => {
+            parent.spawn_bundle(TextBundle::from_section(
+                if spectating {
+                    "A player has left"
+                } else {
+                    "Your opponent has left"
+                },
+                TextStyle {
+                    font: asset_server.load("Inconsolata.ttf"),
+                    font_size: 24.0,
+                    color: Color::hex("ebdbb2").unwrap(),
+                },
+            ));
+        }
+        EndGameReason::Draw | EndGameReason::DrawAgreed => {
+            parent.spawn_bundle(TextBundle::from_section(
+                if *reason == EndGameReason::DrawAgreed {
+                    "The players agreed to a draw"
+                } else {
+                    "It's a draw!"
+                },
+                TextStyle {
+                    font: asset_server.load("Inconsolata.ttf"),
+                    font_size: 24.0,
+                    color: Color::hex("ebdbb2").unwrap(),
+                },
+            ));
+        }
+        EndGameReason::PlayerWon { winner } => {
+            let winner_player = game_state.players.get(winner).unwrap();
+            let is_tac_player = winner_player.piece == store::Tile::Tac;
+
+            parent.spawn_bundle(TextBundle::from_section(
+                format!("{} has won!", winner_player.name.clone()),
+                TextStyle {
+                    font: asset_server.load("Inconsolata.ttf"),
+                    font_size: 24.0,
+                    color: if is_tac_player {
+                        Color::hex("d65d0e").unwrap()
+                    } else {
+                        Color::hex("458488").unwrap()
+                    },
+                },
+            ));
+        }
+        EndGameReason::Timeout { player_id } | EndGameReason::Resigned { player_id } => {
+            let name = game_state
+                .players
+                .get(player_id)
+                .map(|player| player.name.as_str())
+                .unwrap_or("A player");
+
+            parent.spawn_bundle(TextBundle::from_section(
+                if let EndGameReason::Resigned { .. } = reason {
+                    format!("{} resigned!", name)
+                } else {
+                    format!("{} ran out of time!", name)
+                },
+                TextStyle {
+                    font: asset_server.load("Inconsolata.ttf"),
+                    font_size: 24.0,
+                    color: Color::hex("ebdbb2").unwrap(),
+                },
+            ));
+        }
+    }
+
+    // Only the players get to decide whether to play again, and only while both of them are still here
+    if !spectating && !matches!(reason, EndGameReason::PlayerLeft { .. }) {
+        spawn_rematch_button(parent, asset_server);
+    }
+}
+
+fn spawn_rematch_button(parent: &mut ChildBuilder, asset_server: &AssetServer) {
+    parent
+        .spawn_bundle(ButtonBundle {
+            style: Style {
+                margin: UiRect {
+                    left: Val::Px(16.0),
+                    ..default()
+                },
+                padding: UiRect::all(Val::Px(8.0)),
+                ..default()
+            },
+            color: Color::hex("3c3836").unwrap().into(),
+            ..default()
+        })
+        .insert(RematchButton)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle::from_section(
+                    "Play again",
+                    TextStyle {
+                        font: asset_server.load("Inconsolata.ttf"),
+                        font_size: 20.0,
+                        color: Color::hex("ebdbb2").unwrap(),
+                    },
+                ))
+                .insert(RematchLabel);
+        });
+}
+
+fn spawn_game_buttons(parent: &mut ChildBuilder, asset_server: &AssetServer) {
+    let button_style = Style {
+        margin: UiRect {
+            left: Val::Px(8.0),
+            right: Val::Px(8.0),
+            ..default()
+        },
+        padding: UiRect::all(Val::Px(8.0)),
+        ..default()
+    };
+    let text_style = TextStyle {
+        font: asset_server.load("Inconsolata.ttf"),
+        font_size: 20.0,
+        color: Color::hex("ebdbb2").unwrap(),
+    };
+
+    parent
+        .spawn_bundle(NodeBundle {
+            color: Color::NONE.into(),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: button_style.clone(),
+                    color: Color::hex("3c3836").unwrap().into(),
+                    ..default()
+                })
+                .insert(TakebackButton)
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(TextBundle::from_section("Take back", text_style.clone()))
+                        .insert(TakebackLabel);
+                });
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: Style {
+                        display: Display::None,
+                        ..button_style
+                    },
+                    color: Color::hex("3c3836").unwrap().into(),
+                    ..default()
+                })
+                .insert(DeclineTakebackButton)
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle::from_section("Play on", text_style.clone()));
+                });
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: button_style.clone(),
+                    color: Color::hex("3c3836").unwrap().into(),
+                    ..default()
+                })
+                .insert(DrawButton)
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(TextBundle::from_section("Offer draw", text_style.clone()))
+                        .insert(DrawLabel);
+                });
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: button_style,
+                    color: Color::hex("3c3836").unwrap().into(),
+                    ..default()
+                })
+                .insert(ResignButton)
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle::from_section("Resign", text_style));
+                });
+        });
+}
+
+fn rematch_button(
+    interactions: Query<&Interaction, (Changed<Interaction>, With<RematchButton>)>,
+    game_state: Res<GameState>,
+    session: Option<Res<Session>>,
+    mut actions: EventWriter<PlayerAction>,
+) {
+    let session = match session {
+        Some(session) => session,
+        None => return,
+    };
+
+    for interaction in interactions.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+
+        // Accept the rematch if the opponent asked for one, otherwise ask for one ourselves
+        let message = match game_state.rematch_requested_by {
+            Some(requested_by) if requested_by != session.player_id => ClientMessage::AcceptRematch,
+            _ => ClientMessage::RequestRematch,
+        };
+        actions.send(PlayerAction(message));
+    }
+}
+
+fn update_rematch_label(
+    session: Option<Res<Session>>,
+    mut game_events: EventReader<GameEvent>,
+    mut labels: Query<&mut Text, With<RematchLabel>>,
+) {
+    for event in game_events.iter() {
+        if let GameEvent::RequestRematch { player_id } = event {
+            for mut text in labels.iter_mut() {
+                let is_us = session.as_ref().map(|session| session.player_id) == Some(*player_id);
+                text.sections[0].value = if is_us {
+                    "Waiting for opponent...".to_string()
+                } else {
+                    "Accept rematch".to_string()
+                };
+            }
+        }
+    }
+}
+
+fn takeback_button(
+    interactions: Query<&Interaction, (Changed<Interaction>, With<TakebackButton>)>,
+    game_state: Res<GameState>,
+    session: Option<Res<Session>>,
+    mut actions: EventWriter<PlayerAction>,
+) {
+    let session = match session {
+        Some(session) => session,
+        None => return,
+    };
+
+    for interaction in interactions.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+
+        // Let the opponent take back their move if they asked to, otherwise ask to take back our own
+        let message = match game_state.takeback_requested_by {
+            Some(requested_by) if requested_by != session.player_id => {
+                ClientMessage::AcceptTakeback
+            }
+            _ => ClientMessage::RequestTakeback,
+        };
+        actions.send(PlayerAction(message));
+    }
+}
+
+fn decline_takeback_button(
+    interactions: Query<&Interaction, (Changed<Interaction>, With<DeclineTakebackButton>)>,
+    mut actions: EventWriter<PlayerAction>,
+) {
+    for interaction in interactions.iter() {
+        if *interaction == Interaction::Clicked {
+            actions.send(PlayerAction(ClientMessage::DeclineTakeback));
+        }
+    }
+}
+
+/// Turns the takeback button into an answer while the opponent is asking for a takeback
+fn update_takeback_buttons(
+    game_state: Res<GameState>,
+    session: Option<Res<Session>>,
+    spawned: Query<(), Added<TakebackLabel>>,
+    mut labels: Query<&mut Text, With<TakebackLabel>>,
+    mut decline_buttons: Query<&mut Style, With<DeclineTakebackButton>>,
+) {
+    if !game_state.is_changed() && spawned.is_empty() {
+        return;
+    }
+
+    let player_id = session.map(|session| session.player_id);
+    let (label, display) = match game_state.takeback_requested_by {
+        None => ("Take back", Display::None),
+        Some(requested_by) if Some(requested_by) == player_id => {
+            ("Waiting for opponent...", Display::None)
+        }
+        Some(_) => ("Let them take it back", Display::Flex),
+    };
+    for mut text in labels.iter_mut() {
+        text.sections[0].value = label.to_string();
+    }
+    for mut style in decline_buttons.iter_mut() {
+        style.display = display;
+    }
+}
+
+fn draw_button(
+    interactions: Query<&Interaction, (Changed<Interaction>, With<DrawButton>)>,
+    game_state: Res<GameState>,
+    session: Option<Res<Session>>,
+    mut actions: EventWriter<PlayerAction>,
+) {
+    let session = match session {
+        Some(session) => session,
+        None => return,
+    };
+
+    for interaction in interactions.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+
+        // Take the draw if the opponent offered one, otherwise offer one ourselves
+        let message = match game_state.draw_offered_by {
+            Some(offered_by) if offered_by != session.player_id => ClientMessage::AcceptDraw,
+            _ => ClientMessage::OfferDraw,
+        };
+        actions.send(PlayerAction(message));
+    }
+}
+
+fn update_draw_label(
+    game_state: Res<GameState>,
+    session: Option<Res<Session>>,
+    spawned: Query<(), Added<DrawLabel>>,
+    mut labels: Query<&mut Text, With<DrawLabel>>,
+) {
+    if !game_state.is_changed() && spawned.is_empty() {
+        return;
+    }
+
+    let player_id = session.map(|session| session.player_id);
+    let label = match game_state.draw_offered_by {
+        None => "Offer draw",
+        Some(offered_by) if Some(offered_by) == player_id => "Draw offered...",
+        Some(_) => "Accept draw",
+    };
+    for mut text in labels.iter_mut() {
+        text.sections[0].value = label.to_string();
+    }
+}
+
+fn resign_button(
+    interactions: Query<&Interaction, (Changed<Interaction>, With<ResignButton>)>,
+    mut actions: EventWriter<PlayerAction>,
+) {
+    for interaction in interactions.iter() {
+        if *interaction == Interaction::Clicked {
+            actions.send(PlayerAction(ClientMessage::Resign));
+        }
+    }
+}
+
+fn notify_offers(
+    game_state: Res<GameState>,
+    session: Option<Res<Session>>,
+    mut game_events: EventReader<GameEvent>,
+    mut notices: EventWriter<ShowNotice>,
+) {
+    for event in game_events.iter() {
+        let (player_id, message) = match event {
+            GameEvent::RequestTakeback { player_id } => {
+                (player_id, "wants to take back their last move")
+            }
+            GameEvent::AcceptTakeback { player_id } => (player_id, "agreed to take back a move"),
+            GameEvent::DeclineTakeback { player_id } => (player_id, "wants to play on"),
+            GameEvent::OfferDraw { player_id } => (player_id, "offers a draw"),
+            _ => continue,
+        };
+
+        // We know what we asked for ourselves, no need to tell us
+        let is_us = session.as_ref().map(|session| session.player_id) == Some(*player_id);
+        if let (false, Some(player)) = (is_us, game_state.players.get(player_id)) {
+            notices.send(ShowNotice(format!("{} {}", player.name, message)));
+        }
+    }
+}
+
 fn update_in_game_ui(
     game_state: Res<GameState>,
     mut game_events: EventReader<GameEvent>,
@@ -322,7 +1317,8 @@ fn update_in_game_ui(
             GameEvent::PlaceTile {
                 player_id: _,
                 at: _,
-            } => {
+            }
+            | GameEvent::AcceptTakeback { player_id: _ } => {
                 for (handle, mut text) in player_handles.iter_mut() {
                     let is_active_player = game_state.active_player_id == handle.0;
                     let is_tac_player =
@@ -344,59 +1340,150 @@ fn update_in_game_ui(
     }
 }
 
-////////// RENET NETWORKING //////////
-fn new_renet_client(username: &String) -> anyhow::Result<RenetClient> {
-    let server_addr = format!("{}:{}", env!("HOST"), env!("PORT")).parse()?;
-    let socket = UdpSocket::bind("127.0.0.1:0")?;
-    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-    let client_id = current_time.as_millis() as u64;
-
-    // Place username in user data
-    let mut user_data = [0u8; NETCODE_USER_DATA_BYTES];
-    if username.len() > NETCODE_USER_DATA_BYTES - 8 {
-        panic!("Username is too big");
-    }
-    user_data[0..8].copy_from_slice(&(username.len() as u64).to_le_bytes());
-    user_data[8..username.len() + 8].copy_from_slice(username.as_bytes());
-
-    let client = RenetClient::new(
-        current_time,
-        socket,
-        client_id,
-        RenetConnectionConfig::default(),
-        ClientAuthentication::Unsecure {
-            client_id,
-            protocol_id: crate::PROTOCOL_ID,
-            server_addr,
-            user_data: Some(user_data),
-        },
-    )?;
+fn update_clocks(
+    time: Res<Time>,
+    game_state: Res<GameState>,
+    mut player_handles: Query<(&PlayerHandle, &mut Text)>,
+    mut turn: Local<Option<((Stage, PlayerId, usize), Duration)>>,
+) {
+    // A new turn starts whenever a piece is placed or a game begins. The server keeps the real time,
+    // we just count down from when we heard about the turn starting.
+    let now = time.time_since_startup();
+    let pieces = game_state.piece_count();
+    let (stage, active_player_id) = (game_state.stage, game_state.active_player_id);
+    let current_turn = (stage, active_player_id, pieces);
+    let turn_started_at = match *turn {
+        Some((known_turn, started_at)) if known_turn == current_turn => started_at,
+        _ => {
+            *turn = Some((current_turn, now));
+            now
+        }
+    };
+
+    for (handle, mut text) in player_handles.iter_mut() {
+        // Only the clock of the player whose turn it is is running
+        let elapsed = if stage == Stage::InGame && handle.0 == active_player_id {
+            now - turn_started_at
+        } else {
+            Duration::ZERO
+        };
+        text.sections[1].value = match game_state.time_left_for_move(&handle.0, elapsed) {
+            Some(time_left) => {
+                let seconds = time_left.as_secs_f32().ceil() as u64;
+                format!(" {}:{:02}", seconds / 60, seconds % 60)
+            }
+            None => String::new(),
+        };
+    }
+}
 
-    Ok(client)
+////////// RENET NETWORKING //////////
+fn send_actions_to_server(mut actions: EventReader<PlayerAction>, mut client: ResMut<RenetClient>) {
+    for PlayerAction(message) in actions.iter() {
+        client.send_message(GAME_CHANNEL, bincode::serialize(message).unwrap());
+    }
 }
 
 fn receive_events_from_server(
+    mut commands: Commands,
     mut client: ResMut<RenetClient>,
+    mut connection: ResMut<ClientSession>,
     mut game_state: ResMut<GameState>,
     mut game_events: EventWriter<GameEvent>,
+    mut state_synced: EventWriter<StateSynced>,
+    mut notices: EventWriter<ShowNotice>,
 ) {
-    while let Some(message) = client.receive_message(0) {
-        // Whenever the server sends a message we know that it must be a game event
-        let event: GameEvent = bincode::deserialize(&message).unwrap();
-        trace!("{:#?}", event);
+    while let Some(message) = client.receive_message(GAME_CHANNEL) {
+        let message: ServerMessage = bincode::deserialize(&message).unwrap();
+        trace!("{:#?}", message);
 
-        // We trust the server - It's always been good to us!
-        // No need to validate the events it is sending us
-        game_state.consume(&event);
+        for reply in connection.handle(&mut game_state, &message) {
+            if reply == ClientMessage::RequestResync {
+                warn!("Game state is out of sync with the server, asking for a resync");
+                if !game_state.is_consistent() {
+                    warn!("The history of the game doesn't add up to its state");
+                }
+            }
+            client.send_message(GAME_CHANNEL, bincode::serialize(&reply).unwrap());
+        }
 
-        // Send the event into the bevy event system so systems can react to it
-        game_events.send(event);
+        match message {
+            ServerMessage::Welcome {
+                player_id,
+                session_token,
+            } => {
+                commands.insert_resource(Session {
+                    player_id,
+                    session_token,
+                });
+            }
+            ServerMessage::Leaderboard(entries) => {
+                commands.insert_resource(Leaderboard(entries));
+            }
+            ServerMessage::Spectating => {
+                // Without a session we can't send any actions, so there is nothing more to set up
+                commands.insert_resource(Spectating);
+            }
+            ServerMessage::StateSnapshot(_) => state_synced.send(StateSynced),
+            // Send the event into the bevy event system so systems can react to it
+            ServerMessage::GameEvent(event) => game_events.send(event),
+            ServerMessage::EventRejected { event: _, reason } => {
+                // Let the player know why nothing happened
+                notices.send(ShowNotice(reason.to_string()));
+            }
+            ServerMessage::StateChecksum(_) => {}
+        }
     }
 }
 
-// If there's any error network we just panic 🤷‍♂️
-fn handle_renet_error(mut renet_error: EventReader<RenetError>) {
-    for err in renet_error.iter() {
-        panic!("{}", err);
+// If we lose the connection mid game we try to get back in using our session token.
+// On any other network error we just panic 🤷‍♂️
+fn handle_renet_error(
+    mut commands: Commands,
+    mut renet_error: EventReader<RenetError>,
+    client: Res<RenetClient>,
+    game_state: Res<GameState>,
+    mut connection: ResMut<ClientSession>,
+    reconnecting: Option<Res<Reconnecting>>,
+    mut notices: EventWriter<ShowNotice>,
+) {
+    if client.is_connected() {
+        connection.mark_connected();
+    }
+
+    if let Some(err) = renet_error.iter().last() {
+        // The old client keeps failing until the new one is ready
+        if reconnecting.is_some() {
+            return;
+        }
+        let user_data = match connection.reconnect_as(&game_state, err) {
+            Ok(user_data) => user_data,
+            Err(err) => panic!("{}", err),
+        };
+
+        warn!("Lost connection to the server, reconnecting: {}", err);
+        notices.send(ShowNotice("Connection lost, reconnecting...".to_string()));
+        let server_addr = connection.server_addr;
+        let task =
+            IoTaskPool::get().spawn(async move { net::new_renet_client(server_addr, &user_data) });
+        commands.insert_resource(Reconnecting(task));
+    }
+}
+
+fn finish_reconnecting(mut commands: Commands, reconnecting: Option<ResMut<Reconnecting>>) {
+    let mut reconnecting = match reconnecting {
+        Some(reconnecting) => reconnecting,
+        None => return,
+    };
+    let result = match future::block_on(future::poll_once(&mut reconnecting.0)) {
+        Some(result) => result,
+        None => return,
+    };
+
+    commands.remove_resource::<Reconnecting>();
+    // If we can't even get a connect token the server might be restarting. The next error tries again
+    match result {
+        Ok(client) => commands.insert_resource(client),
+        Err(err) => warn!("Failed to reconnect: {}", err),
     }
 }
@@ -0,0 +1,211 @@
+use crate::{Spectating, StateSynced};
+use bevy::prelude::*;
+use store::replay::Replay;
+use store::GameState;
+
+// How long each event is shown for while the replay is playing
+const SECONDS_PER_STEP: f32 = 1.0;
+// The scrubber stretches across the bottom of the window, this far from the edges
+const SCRUBBER_MARGIN: f32 = 16.0;
+
+/// Plays back a recorded match. Nothing is sent anywhere: the game state is rebuilt from the recorded events
+/// up to whatever point the viewer is looking at, and the board is drawn from it as usual.
+///
+/// Space plays and pauses, the arrow keys step backward and forward,
+/// and clicking the scrubber at the bottom of the window jumps to that point in the match.
+pub struct ReplayPlugin {
+    pub replay: Replay,
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReplayViewer {
+            replay: self.replay.clone(),
+            step: 0,
+            playing: true,
+            timer: Timer::from_seconds(SECONDS_PER_STEP, true),
+        })
+        // Watching a replay is spectating, just a while after the fact
+        .insert_resource(Spectating)
+        .add_startup_system(setup_replay)
+        .add_system(replay_controls)
+        .add_system(scrubber)
+        .add_system(play_replay)
+        .add_system(
+            show_step
+                .after(replay_controls)
+                .after(scrubber)
+                .after(play_replay),
+        );
+    }
+}
+
+struct ReplayViewer {
+    replay: Replay,
+    /// The number of events played up to the point being shown
+    step: usize,
+    playing: bool,
+    timer: Timer,
+}
+
+impl ReplayViewer {
+    fn last_step(&self) -> usize {
+        self.replay.events.len()
+    }
+}
+
+#[derive(Component)]
+struct Scrubber;
+
+#[derive(Component)]
+struct ScrubberFill;
+
+#[derive(Component)]
+struct ReplayStatus;
+
+fn setup_replay(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load("Inconsolata.ttf"),
+                    font_size: 20.0,
+                    color: Color::hex("ebdbb2").unwrap(),
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(SCRUBBER_MARGIN),
+                    bottom: Val::Px(28.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(ReplayStatus);
+
+    commands
+        .spawn_bundle(ButtonBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(SCRUBBER_MARGIN),
+                    right: Val::Px(SCRUBBER_MARGIN),
+                    bottom: Val::Px(12.0),
+                    ..default()
+                },
+                size: Size::new(Val::Auto, Val::Px(8.0)),
+                ..default()
+            },
+            color: Color::hex("3c3836").unwrap().into(),
+            ..default()
+        })
+        .insert(Scrubber)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(0.0), Val::Percent(100.0)),
+                        ..default()
+                    },
+                    color: Color::hex("ebdbb2").unwrap().into(),
+                    ..default()
+                })
+                .insert(ScrubberFill);
+        });
+}
+
+fn replay_controls(keys: Res<Input<KeyCode>>, mut viewer: ResMut<ReplayViewer>) {
+    if keys.just_pressed(KeyCode::Space) {
+        // Playing from the end starts over
+        if !viewer.playing && viewer.step == viewer.last_step() {
+            viewer.step = 0;
+        }
+        viewer.playing = !viewer.playing;
+        viewer.timer.reset();
+    }
+
+    // Stepping by hand pauses the replay, so it doesn't run off while looking at something
+    if keys.just_pressed(KeyCode::Right) && viewer.step < viewer.last_step() {
+        viewer.playing = false;
+        viewer.step += 1;
+    }
+    if keys.just_pressed(KeyCode::Left) && viewer.step > 0 {
+        viewer.playing = false;
+        viewer.step -= 1;
+    }
+}
+
+fn scrubber(
+    windows: Res<Windows>,
+    interactions: Query<&Interaction, (Changed<Interaction>, With<Scrubber>)>,
+    mut viewer: ResMut<ReplayViewer>,
+) {
+    let window = windows.get_primary().unwrap();
+    for interaction in interactions.iter() {
+        let cursor = match window.cursor_position() {
+            Some(cursor) if *interaction == Interaction::Clicked => cursor,
+            _ => continue,
+        };
+
+        // Jump to the step at the point of the scrubber that was clicked
+        let width = window.width() - 2.0 * SCRUBBER_MARGIN;
+        let fraction = ((cursor.x - SCRUBBER_MARGIN) / width).clamp(0.0, 1.0);
+        viewer.step = (fraction * viewer.last_step() as f32).round() as usize;
+        viewer.timer.reset();
+    }
+}
+
+fn play_replay(time: Res<Time>, mut viewer: ResMut<ReplayViewer>) {
+    // The timer ticks every frame, but only a step moving changes what is shown,
+    // so ticking it mustn't make show_step rebuild the game
+    let ticking = viewer.bypass_change_detection();
+    if !ticking.playing || !ticking.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    if viewer.step < viewer.last_step() {
+        viewer.step += 1;
+    }
+    if viewer.step == viewer.last_step() {
+        viewer.playing = false;
+    }
+}
+
+fn show_step(
+    viewer: Res<ReplayViewer>,
+    mut game_state: ResMut<GameState>,
+    mut state_synced: EventWriter<StateSynced>,
+    mut fill: Query<&mut Style, With<ScrubberFill>>,
+    mut status: Query<&mut Text, With<ReplayStatus>>,
+) {
+    if !viewer.is_changed() {
+        return;
+    }
+
+    // Rebuild the game from the start of the match, the same way a client catches up from a snapshot
+    let state = viewer
+        .replay
+        .state_at(viewer.step)
+        .expect("Replays are checked to play back when they are loaded");
+    if *game_state != state {
+        *game_state = state;
+        state_synced.send(StateSynced);
+    }
+
+    let progress = if viewer.last_step() == 0 {
+        100.0
+    } else {
+        viewer.step as f32 / viewer.last_step() as f32 * 100.0
+    };
+    fill.single_mut().size.width = Val::Percent(progress);
+
+    status.single_mut().sections[0].value = format!(
+        "{} {}/{}",
+        if viewer.playing { "Playing" } else { "Paused" },
+        viewer.step,
+        viewer.last_step()
+    );
+}
@@ -0,0 +1,146 @@
+use log::{info, warn};
+use renet::{ConnectToken, NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use store::net::{ProtocolVersion, CONNECTION_REFUSED, PROTOCOL_VERSION, TOKEN_ISSUED};
+use store::UserData;
+
+// How long a client has to use a connect token before it expires
+const TOKEN_EXPIRE_SECONDS: u64 = 30;
+// How long the server waits for a silent client before timing it out
+const TIMEOUT_SECONDS: i32 = 15;
+// How long the issuer waits on a client asking for a token before giving up on it
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Spawns a thread handing out connect tokens over TCP.
+///
+/// A client asks for a token by sending the protocol id and version it speaks, followed by its user data
+/// encoded with `UserData::to_bytes`. If the client speaks our protocol, the issuer answers with the
+/// client id it picked for the client, followed by the connect token. Otherwise it tells the client why not.
+/// Since the token is signed with the servers private key, the server can trust that the client id
+/// and user data in it were handed out by us.
+/// Every client is answered on a thread of its own, so a slow or silent client doesn't hold up anybody else.
+pub fn spawn_token_issuer(
+    listener: TcpListener,
+    protocol_id: u64,
+    public_addr: SocketAddr,
+    private_key: [u8; NETCODE_KEY_BYTES],
+) {
+    thread::spawn(move || {
+        // Hand out client ids in order, so no two clients ever share one
+        let mut next_client_id: u64 = 1;
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Failed to accept a connection for a connect token: {}", err);
+                    continue;
+                }
+            };
+            let client_id = next_client_id;
+            next_client_id += 1;
+
+            thread::spawn(move || {
+                let result = stream
+                    .set_read_timeout(Some(STREAM_TIMEOUT))
+                    .and_then(|_| stream.set_write_timeout(Some(STREAM_TIMEOUT)))
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| {
+                        issue_token(stream, client_id, protocol_id, public_addr, &private_key)
+                    });
+
+                if let Err(err) = result {
+                    warn!("Failed to issue connect token: {}", err);
+                }
+            });
+        }
+    });
+}
+
+fn issue_token(
+    mut stream: TcpStream,
+    client_id: u64,
+    protocol_id: u64,
+    public_addr: SocketAddr,
+    private_key: &[u8; NETCODE_KEY_BYTES],
+) -> anyhow::Result<()> {
+    let mut client_protocol_id = [0u8; 8];
+    stream.read_exact(&mut client_protocol_id)?;
+    let client_protocol_id = u64::from_le_bytes(client_protocol_id);
+    let mut client_version = [0u8; 4];
+    stream.read_exact(&mut client_version)?;
+    let client_version = ProtocolVersion::from_bytes(client_version);
+
+    // Read the length prefixed user data the client sent
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len) as usize;
+    if len > NETCODE_USER_DATA_BYTES - 8 {
+        anyhow::bail!("user data is too big");
+    }
+    let mut user_data = [0u8; NETCODE_USER_DATA_BYTES];
+    user_data[0..8].copy_from_slice(&(len as u64).to_le_bytes());
+    stream.read_exact(&mut user_data[8..len + 8])?;
+
+    let name = match UserData::from_bytes(&user_data) {
+        Some(data) => data.name,
+        None => anyhow::bail!("user data is malformed"),
+    };
+
+    // Everything the client sent has been read, so it is safe to answer and hang up
+    if let Some(reason) = incompatibility(protocol_id, client_protocol_id, client_version) {
+        info!(
+            "Refused client {} ({}) speaking version {}: {}",
+            client_id, name, client_version, reason
+        );
+        stream.write_all(&[CONNECTION_REFUSED])?;
+        stream.write_all(&(reason.len() as u32).to_le_bytes())?;
+        stream.write_all(reason.as_bytes())?;
+        return Ok(());
+    }
+
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let connect_token = ConnectToken::generate(
+        current_time,
+        protocol_id,
+        TOKEN_EXPIRE_SECONDS,
+        client_id,
+        TIMEOUT_SECONDS,
+        vec![public_addr],
+        Some(&user_data),
+        private_key,
+    )?;
+
+    stream.write_all(&[TOKEN_ISSUED])?;
+    stream.write_all(&client_id.to_le_bytes())?;
+    connect_token.write(&mut stream)?;
+    info!("Issued connect token for client {} ({})", client_id, name);
+
+    Ok(())
+}
+
+/// Explains to a player why their client can't play on this server, if it can't
+fn incompatibility(
+    protocol_id: u64,
+    client_protocol_id: u64,
+    client_version: ProtocolVersion,
+) -> Option<String> {
+    if client_protocol_id != protocol_id {
+        Some("This server doesn't speak the TicTacTussle protocol".to_string())
+    } else if PROTOCOL_VERSION.accepts(client_version) {
+        None
+    } else if client_version.major < PROTOCOL_VERSION.major {
+        Some(format!(
+            "Your client is out of date, please update to {}",
+            PROTOCOL_VERSION
+        ))
+    } else {
+        Some(format!(
+            "The server is out of date, it needs {} to play",
+            client_version
+        ))
+    }
+}
@@ -0,0 +1,39 @@
+use crate::lobby::ClientId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Clients may send this many chat messages within the window below, anything more is dropped
+const MESSAGES_PER_WINDOW: usize = 5;
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// Keeps clients from flooding their rooms with chat
+#[derive(Default)]
+pub struct ChatLimiter {
+    sent: HashMap<ClientId, VecDeque<Instant>>,
+}
+
+impl ChatLimiter {
+    /// Determines whether a client is allowed to send a chat message right now, counting it if so
+    pub fn allow(&mut self, client_id: ClientId) -> bool {
+        let now = Instant::now();
+        let sent = self.sent.entry(client_id).or_default();
+        while sent
+            .front()
+            .map(|sent_at| now - *sent_at > WINDOW)
+            .unwrap_or(false)
+        {
+            sent.pop_front();
+        }
+
+        if sent.len() >= MESSAGES_PER_WINDOW {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+
+    /// Forgets about a client that has disconnected
+    pub fn forget(&mut self, client_id: ClientId) {
+        self.sent.remove(&client_id);
+    }
+}
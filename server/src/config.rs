@@ -0,0 +1,251 @@
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use store::config::{read_config, resolve_addr, DEFAULT_HOST, DEFAULT_PORT};
+use store::net::PROTOCOL_ID;
+use store::{Rules, TimeControl, Variant};
+
+// Every room holds two players and any number of spectators,
+// so this allows for up to 32 games to be played at once
+const MAX_CLIENTS: usize = 64;
+
+// How many times a second the server updates
+const TICK_RATE: u32 = 20;
+
+/// The TicTacTussle server.
+/// Settings are read from the config file if one is given, and anything passed on the command line wins.
+#[derive(Debug, Parser)]
+#[command(name = "server")]
+struct Args {
+    /// A toml file with any of the settings below
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// The host to listen on
+    #[arg(long)]
+    host: Option<String>,
+    /// The port to listen on
+    #[arg(long)]
+    port: Option<u16>,
+    /// The address clients reach the server at, if it isn't the one it listens on. Needed when listening on 0.0.0.0
+    #[arg(long, value_name = "IP:PORT")]
+    public_addr: Option<SocketAddr>,
+    /// How many clients can be connected at once
+    #[arg(long)]
+    max_clients: Option<usize>,
+    /// Clients can only connect to servers with the same protocol id. Changing it turns every client away
+    #[arg(long)]
+    protocol_id: Option<u64>,
+    /// How many times a second the server updates
+    #[arg(long)]
+    tick_rate: Option<u32>,
+    /// One of off, error, warn, info, debug or trace. Falls back to RUST_LOG when not set
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Either classic or ultimate. Ultimate tic-tac-toe has a board of its own
+    #[arg(long)]
+    variant: Option<String>,
+    /// How many tiles wide the board is
+    #[arg(long)]
+    board_width: Option<usize>,
+    /// How many tiles high the board is
+    #[arg(long)]
+    board_height: Option<usize>,
+    /// How many tiles in a row it takes to win
+    #[arg(long)]
+    win_length: Option<usize>,
+    /// Players keep their pieces on rematches instead of swapping sides. Pass --keep-sides=false to swap anyway
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    keep_sides: Option<bool>,
+    /// How many seconds a player has for a single move
+    #[arg(long)]
+    move_seconds: Option<u64>,
+    /// How many seconds a player has for all of their moves in a game put together
+    #[arg(long)]
+    game_seconds: Option<u64>,
+    /// The file every finished match is kept in. Without one the match history is gone once the server stops
+    #[arg(long, value_name = "PATH")]
+    match_history: Option<PathBuf>,
+    /// The directory replays are saved to. Without one no replays are saved
+    #[arg(long, value_name = "PATH")]
+    replay_dir: Option<PathBuf>,
+}
+
+/// How the server is set up
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Connect tokens point clients to this address. Defaults to the address the server listens on
+    pub public_addr: Option<SocketAddr>,
+    pub max_clients: usize,
+    pub protocol_id: u64,
+    pub tick_rate: u32,
+    pub log_level: Option<String>,
+    pub variant: String,
+    /// The board size and win length default to those of the variant
+    pub board_width: Option<usize>,
+    pub board_height: Option<usize>,
+    pub win_length: Option<usize>,
+    pub keep_sides: bool,
+    /// Games are played without time limits unless these are set
+    pub move_seconds: Option<u64>,
+    pub game_seconds: Option<u64>,
+    /// Nothing is written to disk unless these are set
+    pub match_history: Option<PathBuf>,
+    pub replay_dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            public_addr: None,
+            max_clients: MAX_CLIENTS,
+            protocol_id: PROTOCOL_ID,
+            tick_rate: TICK_RATE,
+            log_level: None,
+            variant: "classic".to_string(),
+            board_width: None,
+            board_height: None,
+            win_length: None,
+            keep_sides: false,
+            move_seconds: None,
+            game_seconds: None,
+            match_history: None,
+            replay_dir: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config from the command line and the config file it points to
+    pub fn load() -> anyhow::Result<Self> {
+        let args = Args::parse();
+        let config: Config = read_config(args.config.as_deref())?;
+        let config = Config {
+            host: args.host.unwrap_or(config.host),
+            port: args.port.unwrap_or(config.port),
+            public_addr: args.public_addr.or(config.public_addr),
+            max_clients: args.max_clients.unwrap_or(config.max_clients),
+            protocol_id: args.protocol_id.unwrap_or(config.protocol_id),
+            tick_rate: args.tick_rate.unwrap_or(config.tick_rate),
+            log_level: args.log_level.or(config.log_level),
+            variant: args.variant.unwrap_or(config.variant),
+            board_width: args.board_width.or(config.board_width),
+            board_height: args.board_height.or(config.board_height),
+            win_length: args.win_length.or(config.win_length),
+            keep_sides: args.keep_sides.unwrap_or(config.keep_sides),
+            move_seconds: args.move_seconds.or(config.move_seconds),
+            game_seconds: args.game_seconds.or(config.game_seconds),
+            match_history: args.match_history.or(config.match_history),
+            replay_dir: args.replay_dir.or(config.replay_dir),
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks that the settings make sense together, so a bad one is reported before the server starts
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // Clients send to the address in their connect token, and nothing can be sent to an unspecified address
+        match self.public_addr {
+            Some(addr) if addr.ip().is_unspecified() => {
+                anyhow::bail!(
+                    "public_addr must be an address clients can reach, got {}",
+                    addr
+                )
+            }
+            None if matches!(self.host.parse::<IpAddr>(), Ok(ip) if ip.is_unspecified()) => {
+                anyhow::bail!(
+                    "Listening on {} needs a public_addr for clients to connect to",
+                    self.host
+                )
+            }
+            _ => {}
+        }
+        if self.max_clients == 0 {
+            anyhow::bail!("max_clients must be at least 1");
+        }
+        if !(1..=1000).contains(&self.tick_rate) {
+            anyhow::bail!(
+                "tick_rate must be between 1 and 1000, got {}",
+                self.tick_rate
+            );
+        }
+        self.log_level_filter()?;
+        if self.move_seconds == Some(0) || self.game_seconds == Some(0) {
+            anyhow::bail!("move_seconds and game_seconds must be at least 1");
+        }
+        self.rules()?;
+
+        Ok(())
+    }
+
+    /// The address to listen on
+    pub fn server_addr(&self) -> anyhow::Result<SocketAddr> {
+        resolve_addr(&self.host, self.port)
+    }
+
+    /// How long the server sleeps between updates
+    pub fn tick(&self) -> Duration {
+        Duration::from_secs(1) / self.tick_rate
+    }
+
+    /// The level to log at, or None to leave it to the RUST_LOG environment variable
+    pub fn log_level_filter(&self) -> anyhow::Result<Option<LevelFilter>> {
+        self.log_level
+            .as_deref()
+            .map(|level| {
+                level.parse().map_err(|_| {
+                    anyhow::anyhow!(
+                        "log_level must be one of off, error, warn, info, debug or trace, got '{}'",
+                        level
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    /// The rules every game on the server is played by
+    pub fn rules(&self) -> anyhow::Result<Rules> {
+        let default = match self.variant.to_lowercase().as_str() {
+            "classic" => Rules::default(),
+            "ultimate" => Rules::ultimate(),
+            variant => anyhow::bail!("variant must be classic or ultimate, got '{}'", variant),
+        };
+        let rules = Rules {
+            width: self.board_width.unwrap_or(default.width),
+            height: self.board_height.unwrap_or(default.height),
+            win_length: self.win_length.unwrap_or(default.win_length),
+            swap_sides_on_rematch: !self.keep_sides,
+            time_control: TimeControl {
+                per_move: self.move_seconds.map(Duration::from_secs),
+                per_game: self.game_seconds.map(Duration::from_secs),
+            },
+            variant: default.variant,
+        };
+        if !rules.is_playable() {
+            match rules.variant {
+                Variant::Classic => anyhow::bail!(
+                    "A {}x{} board with {} in a row can never be won",
+                    rules.width,
+                    rules.height,
+                    rules.win_length
+                ),
+                Variant::Ultimate => anyhow::bail!(
+                    "Ultimate tic-tac-toe is always played on a 9x9 board with 3 in a row, got {}x{} with {}",
+                    rules.width,
+                    rules.height,
+                    rules.win_length
+                ),
+            }
+        }
+
+        Ok(rules)
+    }
+}
@@ -0,0 +1,601 @@
+use chat::ChatLimiter;
+use config::Config;
+use lobby::{Lobby, Room, Seat};
+use log::{info, trace, warn};
+use renet::{RenetServer, ServerAuthentication, ServerConfig, ServerEvent, NETCODE_KEY_BYTES};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use storage::{MatchRecord, MatchStorage, PlayerRecord};
+use store::net::{self, CHAT_CHANNEL, GAME_CHANNEL};
+use store::replay::Replay;
+use store::{
+    ChatMessage, ClientMessage, EndGameReason, GameEvent, Rules, ServerMessage, Stage, UserData,
+    MAX_CHAT_LENGTH, RECONNECT_GRACE_PERIOD,
+};
+
+mod auth;
+mod chat;
+pub mod config;
+mod lobby;
+mod rating;
+pub mod storage;
+
+// How many players are shown on the leaderboard
+const LEADERBOARD_SIZE: usize = 10;
+
+// How often clients are sent a checksum of their game state to compare against
+const CHECKSUM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A TicTacTussle server. It does nothing on its own, call `tick` every now and then to keep it going.
+pub struct Server {
+    server: RenetServer,
+    addr: SocketAddr,
+    lobby: Lobby,
+    archive: Archive,
+    chat_limiter: ChatLimiter,
+    last_updated: Instant,
+    last_checksum: Instant,
+}
+
+impl Server {
+    /// Starts a server on the address in the config, playing games by the given rules.
+    /// Clients get connect tokens from the token issuer, which is started alongside the server
+    /// and listens for TCP connections on the same address.
+    /// Use port 0 to pick any free port. `addr` tells which one was picked.
+    pub fn bind(
+        config: &Config,
+        rules: Rules,
+        storage: Box<dyn MatchStorage>,
+    ) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(config.server_addr()?)?;
+        let addr = socket.local_addr()?;
+        // The private key never leaves this process, so a fresh one is made for every server
+        let private_key: [u8; NETCODE_KEY_BYTES] = rand::random();
+        // Clients connect to whatever address is in their connect token
+        let public_addr = config.public_addr.unwrap_or(addr);
+        auth::spawn_token_issuer(
+            TcpListener::bind(addr)?,
+            config.protocol_id,
+            public_addr,
+            private_key,
+        );
+
+        let server = RenetServer::new(
+            // Pass the current time to renet, so it can use it to order messages
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?,
+            // Pass a server configuration specifying how many clients we allow to connect
+            // and that clients need a connect token signed with our private key to get in.
+            ServerConfig::new(
+                config.max_clients,
+                config.protocol_id,
+                public_addr,
+                ServerAuthentication::Secure { private_key },
+            ),
+            // Pass the connection configuration shared with the clients. On top of renet's default reliable,
+            // unreliable and blocking channels it has a reliable channel for chat.
+            net::connection_config(),
+            socket,
+        )?;
+
+        Ok(Self {
+            server,
+            addr,
+            lobby: Lobby::new(rules),
+            archive: Archive {
+                storage,
+                replay_dir: None,
+            },
+            chat_limiter: ChatLimiter::default(),
+            last_updated: Instant::now(),
+            last_checksum: Instant::now(),
+        })
+    }
+
+    /// The address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Saves replays of finished matches to the given directory, or stops saving them
+    pub fn set_replay_dir(&mut self, dir: Option<PathBuf>) {
+        self.archive.replay_dir = dir;
+    }
+
+    /// Receives whatever the clients have sent since the last tick, plays out the game
+    /// and sends the results back to the clients
+    pub fn tick(&mut self) -> anyhow::Result<()> {
+        // Update server time
+        let now = Instant::now();
+        self.server.update(now - self.last_updated)?;
+        self.last_updated = now;
+
+        self.handle_connection_events();
+        self.forfeit_expired_seats();
+        self.receive_game_messages();
+        self.enforce_clocks();
+        self.receive_chat();
+        self.send_checksums();
+
+        self.server.send_packets()?;
+        Ok(())
+    }
+
+    /// Handles clients connecting to and disconnecting from the server
+    fn handle_connection_events(&mut self) {
+        while let Some(event) = self.server.get_event() {
+            match event {
+                ServerEvent::ClientConnected(id, user_data) => {
+                    let user_data = match UserData::from_bytes(&user_data[..]) {
+                        Some(user_data) => user_data,
+                        None => {
+                            warn!("Client {} sent malformed user data", id);
+                            self.server.disconnect(id);
+                            continue;
+                        }
+                    };
+
+                    // Clients presenting a session token are reconnecting to a seat held for them
+                    if let Some(session_token) = user_data.session_token {
+                        let seat = match self.lobby.resume(id, session_token) {
+                            Some(seat) => seat,
+                            None => {
+                                info!("Client {} tried to resume an unknown session", id);
+                                self.server.disconnect(id);
+                                continue;
+                            }
+                        };
+                        let room = self.lobby.room_mut(seat.room_id).unwrap();
+                        info!(
+                            "Client {} reconnected as player {} in room {}.",
+                            id, seat.player_id, seat.room_id
+                        );
+
+                        let welcome = ServerMessage::Welcome {
+                            player_id: seat.player_id,
+                            session_token,
+                        };
+                        send_to_client(&mut self.server, id, &welcome);
+
+                        // Send the whole state of the game, so the client can catch up
+                        let snapshot = ServerMessage::StateSnapshot(room.game_state.clone());
+                        send_to_client(&mut self.server, id, &snapshot);
+
+                        let event = GameEvent::PlayerReconnected {
+                            player_id: seat.player_id,
+                        };
+                        play_event(&mut self.server, &mut self.archive, room, event);
+                        continue;
+                    }
+
+                    // Find a room for the client to play in
+                    let (session_token, seat) = match self.lobby.join(id, user_data.room.as_deref())
+                    {
+                        Some(joined) => joined,
+                        None => {
+                            // The room is full, so the client gets to watch the game instead
+                            let room_name = user_data.room.as_deref().unwrap();
+                            let room_id = self.lobby.spectate(id, room_name).unwrap();
+                            let room = self.lobby.room_mut(room_id).unwrap();
+                            info!("Client {} is spectating room {}.", id, room_id);
+
+                            send_to_client(&mut self.server, id, &ServerMessage::Spectating);
+                            let snapshot = ServerMessage::StateSnapshot(room.game_state.clone());
+                            send_to_client(&mut self.server, id, &snapshot);
+                            continue;
+                        }
+                    };
+                    let room = self.lobby.room_mut(seat.room_id).unwrap();
+                    info!("Client {} connected and joined room {}.", id, seat.room_id);
+
+                    // Tell the recently joined player who they are and what the game looks like
+                    let welcome = ServerMessage::Welcome {
+                        player_id: seat.player_id,
+                        session_token,
+                    };
+                    send_to_client(&mut self.server, id, &welcome);
+                    let snapshot = ServerMessage::StateSnapshot(room.game_state.clone());
+                    send_to_client(&mut self.server, id, &snapshot);
+
+                    // Add the new player to the game and tell everyone in the room about it
+                    let event = GameEvent::PlayerJoined {
+                        player_id: seat.player_id,
+                        name: user_data.name,
+                    };
+                    // The game begins on its own once the room is full
+                    play_event(&mut self.server, &mut self.archive, room, event);
+                    if room.game_state.stage == Stage::InGame {
+                        trace!("The game in room {} has begun", seat.room_id);
+                    }
+                }
+                ServerEvent::ClientDisconnected(id) => {
+                    info!("Client {} disconnected", id);
+                    self.chat_limiter.forget(id);
+                    // Spectators can just leave, nobody is waiting for them
+                    if self.lobby.stop_spectating(id).is_some() {
+                        continue;
+                    }
+                    let (session_token, seat) = match self.lobby.disconnect(id) {
+                        Some(disconnected) => disconnected,
+                        None => continue,
+                    };
+                    let room = self.lobby.room_mut(seat.room_id).unwrap();
+
+                    if room.game_state.stage == Stage::InGame {
+                        // Hold on to the seat for a while, the player might just have had a hiccup.
+                        // If they come back with their session token they can pick up where they left off.
+                        let event = GameEvent::PlayerConnectionLost {
+                            player_id: seat.player_id,
+                        };
+                        play_event(&mut self.server, &mut self.archive, room, event);
+                    } else {
+                        self.lobby.release(session_token);
+                        leave_room(&mut self.server, &mut self.archive, &mut self.lobby, seat);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Players that haven't reconnected within the grace period forfeit the game
+    fn forfeit_expired_seats(&mut self) {
+        for session_token in self.lobby.expired_seats(RECONNECT_GRACE_PERIOD) {
+            if let Some(seat) = self.lobby.release(session_token) {
+                info!("Player {} did not reconnect in time", seat.player_id);
+                leave_room(&mut self.server, &mut self.archive, &mut self.lobby, seat);
+            }
+        }
+    }
+
+    /// Receives messages from clients. Valid events are broadcast to the room they were sent in
+    fn receive_game_messages(&mut self) {
+        for client_id in self.server.clients_id().into_iter() {
+            while let Some(message) = self.server.receive_message(client_id, GAME_CHANNEL) {
+                let message = match bincode::deserialize::<ClientMessage>(&message) {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+
+                // Anyone can have a look at the leaderboard, whether they are in a game or not
+                if let ClientMessage::RequestLeaderboard = message {
+                    match self.archive.storage.leaderboard(LEADERBOARD_SIZE) {
+                        Ok(entries) => {
+                            let leaderboard = ServerMessage::Leaderboard(entries);
+                            send_to_client(&mut self.server, client_id, &leaderboard);
+                        }
+                        Err(err) => warn!("Failed to look up the leaderboard: {}", err),
+                    }
+                    continue;
+                }
+
+                // Clients that aren't in a room have no game to send messages about
+                let seat = self.lobby.seat_of(client_id);
+                let room = match self
+                    .lobby
+                    .room_of(client_id)
+                    .and_then(|id| self.lobby.room_mut(id))
+                {
+                    Some(room) => room,
+                    None => continue,
+                };
+
+                if let ClientMessage::RequestResync = message {
+                    // The client has fallen out of sync, so send it the whole state of its game
+                    info!("Client {} asked for a resync", client_id);
+                    let snapshot = ServerMessage::StateSnapshot(room.game_state.clone());
+                    send_to_client(&mut self.server, client_id, &snapshot);
+                    continue;
+                }
+
+                // Spectators only get to watch
+                let seat = match seat {
+                    Some(seat) => seat,
+                    None => {
+                        warn!("Spectator {} tried to take part in the game", client_id);
+                        continue;
+                    }
+                };
+
+                // The player is whoever sits in the seat of the connection, never whoever the message claims to be
+                let event = match message.into_event(seat.player_id) {
+                    Some(event) => event,
+                    None => continue,
+                };
+
+                if let Err(reason) = room.game_state.validate(&event) {
+                    warn!(
+                        "Player {} sent invalid event ({}):\n\t{:#?}",
+                        client_id, reason, event
+                    );
+                    // Let the client know why its event was rejected
+                    let rejection = ServerMessage::EventRejected { event, reason };
+                    send_to_client(&mut self.server, client_id, &rejection);
+                    continue;
+                }
+                trace!("Player {} sent:\n\t{:#?}", client_id, event);
+
+                // Moves only count if they are made in time
+                if let GameEvent::PlaceTile { player_id, at: _ } = event {
+                    if enforce_clock(&mut self.server, &mut self.archive, room) {
+                        continue;
+                    }
+                    // Only the clock for the whole game carries over from one move to the next
+                    if room.game_state.rules.time_control.per_game.is_some() {
+                        let elapsed = room.turn_started_at.elapsed();
+                        let event = GameEvent::SpendTime { player_id, elapsed };
+                        play_event(&mut self.server, &mut self.archive, room, event);
+                        // The time spent may have been the last the player had left
+                        if room.game_state.stage != Stage::InGame {
+                            continue;
+                        }
+                    }
+                }
+                // Whether the move won the game is worked out along with it
+                play_event(&mut self.server, &mut self.archive, room, event);
+            }
+        }
+    }
+
+    /// Players that take too long to move lose the game
+    fn enforce_clocks(&mut self) {
+        for room in self.lobby.rooms_mut() {
+            enforce_clock(&mut self.server, &mut self.archive, room);
+        }
+    }
+
+    /// Receives chat from players and passes it on to everyone in their room
+    fn receive_chat(&mut self) {
+        for client_id in self.server.clients_id().into_iter() {
+            while let Some(message) = self.server.receive_message(client_id, CHAT_CHANNEL) {
+                let message = match bincode::deserialize::<ChatMessage>(&message) {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+
+                // Spectators only get to watch, so only players can chat
+                let seat = match self.lobby.seat_of(client_id) {
+                    Some(seat) => seat,
+                    None => continue,
+                };
+                let room = self.lobby.room_mut(seat.room_id).unwrap();
+                let sender = match room.game_state.players.get(&seat.player_id) {
+                    Some(player) => player.name.clone(),
+                    None => continue,
+                };
+
+                let text = message.text.trim();
+                let rejection = if text.is_empty() {
+                    continue;
+                } else if text.chars().count() > MAX_CHAT_LENGTH {
+                    Some(format!(
+                        "Messages can be at most {} characters long",
+                        MAX_CHAT_LENGTH
+                    ))
+                } else if !self.chat_limiter.allow(client_id) {
+                    Some("You are sending messages too quickly".to_string())
+                } else {
+                    None
+                };
+                if let Some(reason) = rejection {
+                    let message = ChatMessage {
+                        sender: None,
+                        text: reason,
+                    };
+                    let message = bincode::serialize(&message).unwrap();
+                    self.server.send_message(client_id, CHAT_CHANNEL, message);
+                    continue;
+                }
+
+                let message = ChatMessage {
+                    sender: Some(sender),
+                    text: text.to_string(),
+                };
+                send_chat_to_room(&mut self.server, room, &message);
+            }
+        }
+    }
+
+    /// Every now and then, lets clients check that they agree with us on the state of their game
+    fn send_checksums(&mut self) {
+        if self.last_checksum.elapsed() >= CHECKSUM_INTERVAL {
+            for room in self.lobby.rooms() {
+                let checksum = ServerMessage::StateChecksum(room.game_state.checksum());
+                send_to_room(&mut self.server, room, &checksum);
+            }
+            self.last_checksum = Instant::now();
+        }
+    }
+}
+
+/// Utility function for sending a message to a single client
+fn send_to_client(server: &mut RenetServer, client_id: u64, message: &ServerMessage) {
+    server.send_message(
+        client_id,
+        GAME_CHANNEL,
+        bincode::serialize(message).unwrap(),
+    );
+}
+
+/// Utility function for sending a message to every client in a room, including spectators
+fn send_to_room(server: &mut RenetServer, room: &Room, message: &ServerMessage) {
+    let message = bincode::serialize(message).unwrap();
+    for client_id in room.clients.iter().chain(room.spectators.iter()) {
+        server.send_message(*client_id, GAME_CHANNEL, message.clone());
+    }
+}
+
+/// Utility function for sending a chat message to every client in a room, including spectators
+fn send_chat_to_room(server: &mut RenetServer, room: &Room, message: &ChatMessage) {
+    let message = bincode::serialize(message).unwrap();
+    for client_id in room.clients.iter().chain(room.spectators.iter()) {
+        server.send_message(*client_id, CHAT_CHANNEL, message.clone());
+    }
+}
+
+/// Utility function for playing an event in a room and telling every client in the room about it.
+/// Everything that follows from the event is played along with it, like the game ending when a player leaves.
+/// Matches are recorded as they are played, so they can be archived once they end.
+/// Events should have been validated already, an invalid one is logged and otherwise ignored.
+fn play_event(server: &mut RenetServer, archive: &mut Archive, room: &mut Room, event: GameEvent) {
+    // A match may begin part way through the events, so keep track of the state right before each one
+    let mut snapshot = room.game_state.clone();
+    let events = match room.game_state.apply(event.clone()) {
+        Ok(events) => events,
+        Err(reason) => {
+            warn!(
+                "Tried to play an invalid event ({}):\n\t{:#?}",
+                reason, event
+            );
+            return;
+        }
+    };
+    for event in events {
+        if let GameEvent::BeginGame { .. } | GameEvent::AcceptRematch { .. } = event {
+            room.replay = Some(Replay::record(&snapshot));
+        }
+        snapshot.consume(&event);
+
+        // Start the clock of the player whose turn it is now
+        if let GameEvent::BeginGame { .. }
+        | GameEvent::AcceptRematch { .. }
+        | GameEvent::AcceptTakeback { .. }
+        | GameEvent::PlaceTile { .. } = event
+        {
+            room.turn_started_at = Instant::now();
+        }
+        if let Some(replay) = room.replay.as_mut() {
+            replay.events.push(event.clone());
+        }
+
+        send_to_room(server, room, &ServerMessage::GameEvent(event.clone()));
+
+        if let GameEvent::EndGame { reason } = event {
+            // Replaying the whole history is too slow to do after every move, but once a game is fine
+            debug_assert!(
+                room.game_state.is_consistent(),
+                "The history of the game no longer adds up to its state"
+            );
+            if let Some(replay) = room.replay.take() {
+                archive.record(replay, &reason);
+            }
+        }
+    }
+}
+
+/// Where finished matches end up
+struct Archive {
+    storage: Box<dyn MatchStorage>,
+    /// Where replays of finished matches are saved, if anywhere
+    replay_dir: Option<PathBuf>,
+}
+
+impl Archive {
+    /// Saves a replay of a finished match and adds it to the match history
+    fn record(&mut self, mut replay: Replay, reason: &EndGameReason) {
+        replay.finish();
+        if let Some(dir) = &self.replay_dir {
+            save_replay(dir, &replay);
+        }
+
+        let record = MatchRecord::new(&replay, reason);
+        if let Err(err) = self.storage.record_match(record.clone()) {
+            warn!("Failed to record match: {}", err);
+            return;
+        }
+        for name in record.players.iter() {
+            if let (
+                Ok(PlayerRecord {
+                    wins,
+                    losses,
+                    draws,
+                }),
+                Ok(rating),
+            ) = (self.storage.player_record(name), self.storage.rating(name))
+            {
+                info!(
+                    "{} has won {}, lost {} and drawn {} games, and is now rated {:.0}",
+                    name, wins, losses, draws, rating
+                );
+            }
+        }
+    }
+}
+
+/// Writes a replay to the given directory
+fn save_replay(dir: &Path, replay: &Replay) {
+    let player_ids: Vec<String> = replay.players.keys().map(u64::to_string).collect();
+    let name = format!("{}-{}", replay.started_at, player_ids.join("-"));
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        warn!(
+            "Failed to create the replay directory {}: {}",
+            dir.display(),
+            err
+        );
+        return;
+    }
+
+    // The same players can start more than one match within a second, so number any that come after the first
+    for attempt in 1.. {
+        let path = match attempt {
+            1 => dir.join(format!("{}.json", name)),
+            _ => dir.join(format!("{}-{}.json", name, attempt)),
+        };
+        match replay.save(&path) {
+            Ok(()) => info!("Saved replay to {}", path.display()),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => warn!("Failed to save replay to {}: {}", path.display(), err),
+        }
+        return;
+    }
+}
+
+/// Removes a player from their room for good, ending the game if it was in progress
+fn leave_room(server: &mut RenetServer, archive: &mut Archive, lobby: &mut Lobby, seat: Seat) {
+    let room = match lobby.room_mut(seat.room_id) {
+        Some(room) => room,
+        None => return,
+    };
+
+    // If the game was in progress it ends along with the player leaving
+    let event = GameEvent::PlayerDisconnected {
+        player_id: seat.player_id,
+    };
+    play_event(server, archive, room, event);
+
+    // Nobody is going to play in a room that is empty, or rematch once a player has left
+    if room.clients.is_empty() || room.game_state.stage == Stage::Ended {
+        lobby.remove_room(seat.room_id);
+        trace!("Room {} was torn down", seat.room_id);
+    }
+}
+
+/// Ends the game if the active player has run out of time. Returns whether the game was ended
+fn enforce_clock(server: &mut RenetServer, archive: &mut Archive, room: &mut Room) -> bool {
+    let game_state = &room.game_state;
+    if game_state.stage != Stage::InGame {
+        return false;
+    }
+
+    let player_id = game_state.active_player_id;
+    let elapsed = room.turn_started_at.elapsed();
+    if game_state.time_left_for_move(&player_id, elapsed) != Some(Duration::ZERO) {
+        return false;
+    }
+
+    info!("Player {} ran out of time", player_id);
+    // Take the time off the clock for the whole game first, so everyone sees it run out.
+    // That ends the game on its own if the player is out of time for the whole game,
+    // otherwise it is the time for the move that ran out.
+    if room.game_state.rules.time_control.per_game.is_some() {
+        let event = GameEvent::SpendTime { player_id, elapsed };
+        play_event(server, archive, room, event);
+    }
+    if room.game_state.stage == Stage::InGame {
+        let event = GameEvent::EndGame {
+            reason: EndGameReason::Timeout { player_id },
+        };
+        play_event(server, archive, room, event);
+    }
+    true
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use store::replay::Replay;
+use store::{GameState, PlayerId, Rules, SessionToken, Stage};
+
+pub type RoomId = u64;
+pub type ClientId = u64;
+
+// Tic tac toe is a two player game, so that is how many clients fit in a room
+const PLAYERS_PER_ROOM: usize = 2;
+
+/// A single game of TicTacTussle and the clients taking part in it
+pub struct Room {
+    /// Rooms with a name can be joined by anyone that knows it.
+    /// Rooms without a name are used for pairing up random opponents.
+    pub name: Option<String>,
+    pub game_state: GameState,
+    /// The clients currently connected to the room
+    pub clients: Vec<ClientId>,
+    /// Clients watching the game without taking part in it
+    pub spectators: Vec<ClientId>,
+    /// The recording of the match being played, if one is in progress
+    pub replay: Option<Replay>,
+    /// When the active player got the turn, for keeping track of how long they are taking
+    pub turn_started_at: Instant,
+}
+
+impl Room {
+    fn new(name: Option<String>, rules: Rules) -> Self {
+        Self {
+            name,
+            game_state: GameState::new(rules),
+            clients: Vec::new(),
+            spectators: Vec::new(),
+            replay: None,
+            turn_started_at: Instant::now(),
+        }
+    }
+
+    /// Determines whether another client can join the room
+    fn is_open(&self) -> bool {
+        self.game_state.stage == Stage::PreGame && self.clients.len() < PLAYERS_PER_ROOM
+    }
+}
+
+/// A players place in a room. Seats outlive connections, so a player whose connection drops
+/// can pick up where they left off by presenting the session token of their seat.
+#[derive(Debug, Clone, Copy)]
+pub struct Seat {
+    pub room_id: RoomId,
+    pub player_id: PlayerId,
+    /// The client sitting in the seat, or None if the seat is being held for a disconnected player
+    client_id: Option<ClientId>,
+    disconnected_at: Option<Instant>,
+}
+
+/// Keeps track of all the rooms on the server and which clients are sitting in which rooms
+pub struct Lobby {
+    rules: Rules,
+    rooms: HashMap<RoomId, Room>,
+    seats: HashMap<SessionToken, Seat>,
+    client_sessions: HashMap<ClientId, SessionToken>,
+    spectating: HashMap<ClientId, RoomId>,
+    next_room_id: RoomId,
+}
+
+impl Lobby {
+    /// Creates an empty lobby. Every room created by the lobby is played by the given rules
+    pub fn new(rules: Rules) -> Self {
+        Self {
+            rules,
+            rooms: HashMap::new(),
+            seats: HashMap::new(),
+            client_sessions: HashMap::new(),
+            spectating: HashMap::new(),
+            next_room_id: 0,
+        }
+    }
+
+    /// Gives a client a seat in a room.
+    /// If the client asked for a named room it joins that room, creating it if it doesn't exist yet.
+    /// Otherwise it is paired with a client waiting in an unnamed room, or gets a new room to wait in.
+    /// Returns None if the requested room is full.
+    pub fn join(
+        &mut self,
+        client_id: ClientId,
+        room_name: Option<&str>,
+    ) -> Option<(SessionToken, Seat)> {
+        let existing = self
+            .rooms
+            .iter()
+            .find(|(_, room)| match room_name {
+                Some(name) => room.name.as_deref() == Some(name),
+                None => room.name.is_none() && room.is_open(),
+            })
+            .map(|(room_id, _)| *room_id);
+
+        let room_id = match existing {
+            Some(room_id) => room_id,
+            None => self.create_room(room_name.map(String::from)),
+        };
+
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        if !room.is_open() {
+            return None;
+        }
+        room.clients.push(client_id);
+
+        // The client that first takes a seat decides the id of the player sitting in it
+        let seat = Seat {
+            room_id,
+            player_id: client_id,
+            client_id: Some(client_id),
+            disconnected_at: None,
+        };
+        let token = rand::random::<SessionToken>();
+        self.seats.insert(token, seat);
+        self.client_sessions.insert(client_id, token);
+
+        Some((token, seat))
+    }
+
+    /// Lets a client watch the game in a named room without taking a seat.
+    /// Returns None if there is no room by that name.
+    pub fn spectate(&mut self, client_id: ClientId, room_name: &str) -> Option<RoomId> {
+        let (room_id, room) = self
+            .rooms
+            .iter_mut()
+            .find(|(_, room)| room.name.as_deref() == Some(room_name))?;
+        room.spectators.push(client_id);
+        self.spectating.insert(client_id, *room_id);
+
+        Some(*room_id)
+    }
+
+    /// Stops a client from watching the room it was spectating
+    pub fn stop_spectating(&mut self, client_id: ClientId) -> Option<RoomId> {
+        let room_id = self.spectating.remove(&client_id)?;
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            room.spectators.retain(|id| *id != client_id);
+        }
+
+        Some(room_id)
+    }
+
+    /// Puts a client back in the held seat belonging to a session token.
+    /// Returns None if there is no such seat or if somebody is already sitting in it.
+    pub fn resume(&mut self, client_id: ClientId, token: SessionToken) -> Option<Seat> {
+        let seat = self.seats.get_mut(&token)?;
+        if seat.client_id.is_some() {
+            return None;
+        }
+        let room = self.rooms.get_mut(&seat.room_id)?;
+
+        room.clients.push(client_id);
+        seat.client_id = Some(client_id);
+        seat.disconnected_at = None;
+        self.client_sessions.insert(client_id, token);
+
+        Some(*seat)
+    }
+
+    /// Takes a disconnected client out of its room, but keeps its seat so it can be resumed.
+    /// Returns the session token of the seat so it can be released if it shouldn't be held.
+    pub fn disconnect(&mut self, client_id: ClientId) -> Option<(SessionToken, Seat)> {
+        let token = self.client_sessions.remove(&client_id)?;
+        let seat = self.seats.get_mut(&token)?;
+        if let Some(room) = self.rooms.get_mut(&seat.room_id) {
+            room.clients.retain(|id| *id != client_id);
+        }
+        seat.client_id = None;
+        seat.disconnected_at = Some(Instant::now());
+
+        Some((token, *seat))
+    }
+
+    /// Gives up a seat for good
+    pub fn release(&mut self, token: SessionToken) -> Option<Seat> {
+        let seat = self.seats.remove(&token)?;
+        if let Some(client_id) = seat.client_id {
+            self.client_sessions.remove(&client_id);
+        }
+
+        Some(seat)
+    }
+
+    /// Finds the seats that have been held for longer than the given grace period
+    pub fn expired_seats(&self, grace_period: Duration) -> Vec<SessionToken> {
+        self.seats
+            .iter()
+            .filter(|(_, seat)| {
+                seat.disconnected_at
+                    .map(|disconnected_at| disconnected_at.elapsed() > grace_period)
+                    .unwrap_or(false)
+            })
+            .map(|(token, _)| *token)
+            .collect()
+    }
+
+    /// Finds the seat a client is sitting in
+    pub fn seat_of(&self, client_id: ClientId) -> Option<Seat> {
+        let token = self.client_sessions.get(&client_id)?;
+        self.seats.get(token).copied()
+    }
+
+    /// Finds the room a client is in, whether it is playing or spectating
+    pub fn room_of(&self, client_id: ClientId) -> Option<RoomId> {
+        match self.seat_of(client_id) {
+            Some(seat) => Some(seat.room_id),
+            None => self.spectating.get(&client_id).copied(),
+        }
+    }
+
+    /// Iterates over every room on the server
+    pub fn rooms(&self) -> impl Iterator<Item = &Room> {
+        self.rooms.values()
+    }
+
+    /// Iterates over every room on the server, allowing them to be changed
+    pub fn rooms_mut(&mut self) -> impl Iterator<Item = &mut Room> {
+        self.rooms.values_mut()
+    }
+
+    pub fn room_mut(&mut self, room_id: RoomId) -> Option<&mut Room> {
+        self.rooms.get_mut(&room_id)
+    }
+
+    /// Tears down a room, releasing every seat in it and sending its spectators away
+    pub fn remove_room(&mut self, room_id: RoomId) -> Option<Room> {
+        let room = self.rooms.remove(&room_id)?;
+        for client_id in room.spectators.iter() {
+            self.spectating.remove(client_id);
+        }
+        let tokens: Vec<SessionToken> = self
+            .seats
+            .iter()
+            .filter(|(_, seat)| seat.room_id == room_id)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            self.release(token);
+        }
+
+        Some(room)
+    }
+
+    fn create_room(&mut self, name: Option<String>) -> RoomId {
+        let room_id = self.next_room_id;
+        self.next_room_id += 1;
+        self.rooms.insert(room_id, Room::new(name, self.rules));
+        room_id
+    }
+}
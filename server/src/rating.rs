@@ -0,0 +1,47 @@
+use crate::storage::{MatchRecord, Outcome};
+use std::collections::HashMap;
+
+// The rating of a player that hasn't played any matches yet
+pub const INITIAL_RATING: f64 = 1200.0;
+// How much a single match can move a rating. Higher values make ratings settle quicker, but jumpier
+const K_FACTOR: f64 = 32.0;
+
+/// The Elo ratings of every player that has finished a match, by name
+#[derive(Debug, Default)]
+pub struct Ratings(HashMap<String, f64>);
+
+impl Ratings {
+    pub fn get(&self, name: &str) -> f64 {
+        self.0.get(name).copied().unwrap_or(INITIAL_RATING)
+    }
+
+    /// Iterates over every rated player and their rating
+    pub fn iter(&self) -> impl Iterator<Item = (&String, f64)> {
+        self.0.iter().map(|(name, rating)| (name, *rating))
+    }
+
+    /// Updates the ratings of the players in a finished match
+    pub fn apply(&mut self, record: &MatchRecord) {
+        // Two players going by the same name share a rating, which a match between them can't move
+        let (a, b) = match record.players.as_slice() {
+            [a, b] if a != b => (a, b),
+            _ => return,
+        };
+        // Score is 1 for a win, 0 for a loss and a half for a draw
+        let score_a = match &record.outcome {
+            Outcome::Draw => 0.5,
+            _ if record.won_by(a) => 1.0,
+            _ => 0.0,
+        };
+
+        let (rating_a, rating_b) = (self.get(a), self.get(b));
+        let change = K_FACTOR * (score_a - expected_score(rating_a, rating_b));
+        self.0.insert(a.clone(), rating_a + change);
+        self.0.insert(b.clone(), rating_b - change);
+    }
+}
+
+/// The score a player is expected to get against an opponent, from 0 for a sure loss to 1 for a sure win
+fn expected_score(rating: f64, opponent_rating: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_rating - rating) / 400.0))
+}
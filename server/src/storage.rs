@@ -0,0 +1,249 @@
+use crate::rating::Ratings;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use store::replay::Replay;
+use store::{EndGameReason, LeaderboardEntry};
+
+/// How a match ended
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Outcome {
+    Won {
+        winner: String,
+    },
+    Draw,
+    /// A player left before the match was over, handing the win to their opponent
+    Forfeited {
+        by: String,
+    },
+    /// A player ran out of time, handing the win to their opponent
+    TimedOut {
+        by: String,
+    },
+    /// A player gave up, handing the win to their opponent
+    Resigned {
+        by: String,
+    },
+}
+
+/// A finished match. Players are known by name, since that is all that stays the same between games
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchRecord {
+    pub players: Vec<String>,
+    pub outcome: Outcome,
+    /// When the match began and ended, in seconds since the unix epoch
+    pub started_at: u64,
+    pub ended_at: u64,
+}
+
+impl MatchRecord {
+    /// Describes the match recorded in a replay, which ended for the given reason
+    pub fn new(replay: &Replay, reason: &EndGameReason) -> Self {
+        let name_of = |player_id| replay.players.get(player_id).cloned().unwrap_or_default();
+        let outcome = match reason {
+            EndGameReason::PlayerWon { winner } => Outcome::Won {
+                winner: name_of(winner),
+            },
+            // A draw counts the same whether the board filled up or the players agreed to it
+            EndGameReason::Draw | EndGameReason::DrawAgreed => Outcome::Draw,
+            EndGameReason::PlayerLeft { player_id } => Outcome::Forfeited {
+                by: name_of(player_id),
+            },
+            EndGameReason::Timeout { player_id } => Outcome::TimedOut {
+                by: name_of(player_id),
+            },
+            EndGameReason::Resigned { player_id } => Outcome::Resigned {
+                by: name_of(player_id),
+            },
+        };
+
+        Self {
+            players: replay.players.values().cloned().collect(),
+            outcome,
+            started_at: replay.started_at,
+            ended_at: replay.ended_at,
+        }
+    }
+
+    /// Determines whether the named player won the match
+    pub fn won_by(&self, name: &str) -> bool {
+        match &self.outcome {
+            Outcome::Won { winner } => winner == name,
+            Outcome::Forfeited { by } | Outcome::TimedOut { by } | Outcome::Resigned { by } => {
+                by != name && self.players.iter().any(|p| p == name)
+            }
+            Outcome::Draw => false,
+        }
+    }
+}
+
+/// The number of matches a player has won, lost and drawn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerRecord {
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+/// Somewhere to keep the history of every match played on the server
+pub trait MatchStorage {
+    /// Stores a finished match
+    fn record_match(&mut self, record: MatchRecord) -> anyhow::Result<()>;
+
+    /// Every match the named player has taken part in, oldest first
+    fn matches_of(&self, name: &str) -> anyhow::Result<Vec<MatchRecord>>;
+
+    /// The current Elo rating of the named player
+    fn rating(&self, name: &str) -> anyhow::Result<f64>;
+
+    /// The given number of best rated players, best first
+    fn leaderboard(&self, count: usize) -> anyhow::Result<Vec<LeaderboardEntry>>;
+
+    /// Tallies up the wins, losses and draws of the named player
+    fn player_record(&self, name: &str) -> anyhow::Result<PlayerRecord> {
+        let mut record = PlayerRecord::default();
+        for played in self.matches_of(name)? {
+            if played.outcome == Outcome::Draw {
+                record.draws += 1;
+            } else if played.won_by(name) {
+                record.wins += 1;
+            } else {
+                record.losses += 1;
+            }
+        }
+
+        Ok(record)
+    }
+}
+
+/// Keeps the match history in memory only, so it is gone once the server stops.
+/// Ratings are worked out by playing through the history in order.
+#[derive(Default)]
+pub struct MemoryStorage {
+    matches: Vec<MatchRecord>,
+    ratings: Ratings,
+}
+
+impl MatchStorage for MemoryStorage {
+    fn record_match(&mut self, record: MatchRecord) -> anyhow::Result<()> {
+        self.ratings.apply(&record);
+        self.matches.push(record);
+
+        Ok(())
+    }
+
+    fn matches_of(&self, name: &str) -> anyhow::Result<Vec<MatchRecord>> {
+        Ok(self
+            .matches
+            .iter()
+            .filter(|played| played.players.iter().any(|player| player == name))
+            .cloned()
+            .collect())
+    }
+
+    fn rating(&self, name: &str) -> anyhow::Result<f64> {
+        Ok(self.ratings.get(name))
+    }
+
+    fn leaderboard(&self, count: usize) -> anyhow::Result<Vec<LeaderboardEntry>> {
+        let mut ratings: Vec<(&String, f64)> = self.ratings.iter().collect();
+        // Ties are broken by name, so players with the same rating keep their order between requests
+        ratings.sort_by(|(name_a, a), (name_b, b)| b.total_cmp(a).then_with(|| name_a.cmp(name_b)));
+
+        ratings
+            .into_iter()
+            .take(count)
+            .map(|(name, rating)| {
+                let PlayerRecord {
+                    wins,
+                    losses,
+                    draws,
+                } = self.player_record(name)?;
+                Ok(LeaderboardEntry {
+                    name: name.clone(),
+                    rating: rating.round() as u32,
+                    wins,
+                    losses,
+                    draws,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Stores matches in a file with one json encoded match per line.
+/// The whole history is kept in memory as well, so queries don't have to read the file.
+pub struct FileStorage {
+    path: PathBuf,
+    memory: MemoryStorage,
+}
+
+impl FileStorage {
+    /// Opens the history in the given file, creating the file if it doesn't exist yet.
+    /// A last line that can't be read is skipped, since that is what a crash in the middle of writing leaves behind.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut history = String::new();
+        file.read_to_string(&mut history)?;
+
+        let mut memory = MemoryStorage::default();
+        let lines: Vec<&str> = history.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(line) {
+                Ok(record) => memory.record_match(record)?,
+                Err(err) if i + 1 == lines.len() => {
+                    warn!(
+                        "Skipping the unreadable last match in {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+                Err(err) => anyhow::bail!(
+                    "Match {} in {} is unreadable: {}",
+                    i + 1,
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        // Make sure the next match starts on a line of its own, rather than after a line that was cut off
+        if !history.is_empty() && !history.ends_with('\n') {
+            writeln!(file)?;
+        }
+
+        Ok(Self { path, memory })
+    }
+}
+
+impl MatchStorage for FileStorage {
+    fn record_match(&mut self, record: MatchRecord) -> anyhow::Result<()> {
+        // Append rather than rewriting the file, so a crash can at most lose the match being written
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        self.memory.record_match(record)
+    }
+
+    fn matches_of(&self, name: &str) -> anyhow::Result<Vec<MatchRecord>> {
+        self.memory.matches_of(name)
+    }
+
+    fn rating(&self, name: &str) -> anyhow::Result<f64> {
+        self.memory.rating(name)
+    }
+
+    fn leaderboard(&self, count: usize) -> anyhow::Result<Vec<LeaderboardEntry>> {
+        self.memory.leaderboard(count)
+    }
+}
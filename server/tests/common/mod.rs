@@ -0,0 +1,187 @@
+//! Runs a server and any number of headless clients in the same process, talking over loopback.
+//! Tests script what the clients send and check what they get back.
+
+use renet::RenetClient;
+use server::config::Config;
+use server::storage::MemoryStorage;
+use server::Server;
+use std::thread;
+use std::time::{Duration, Instant};
+use store::net::{self, GAME_CHANNEL};
+use store::{
+    ClientMessage, GameEvent, GameState, PlayerId, Rules, ServerMessage, SessionToken, Stage,
+    UserData, ValidationError,
+};
+
+// How long to wait for something to happen before failing the test
+const TIMEOUT: Duration = Duration::from_secs(5);
+// How long to sleep between ticks, so packets have time to arrive
+const TICK: Duration = Duration::from_millis(5);
+
+/// A client without any graphics, keeping track of everything the server tells it
+pub struct TestClient {
+    client: RenetClient,
+    connected: bool,
+    user_data: UserData,
+    pub player_id: Option<PlayerId>,
+    session_token: Option<SessionToken>,
+    pub game_state: GameState,
+    /// Every message the server has sent, oldest first
+    pub messages: Vec<ServerMessage>,
+}
+
+impl TestClient {
+    /// The game events the server has sent, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &GameEvent> {
+        self.messages.iter().filter_map(|message| match message {
+            ServerMessage::GameEvent(event) => Some(event),
+            _ => None,
+        })
+    }
+
+    /// The reasons the server gave for rejecting events, oldest first
+    pub fn rejections(&self) -> Vec<ValidationError> {
+        self.messages
+            .iter()
+            .filter_map(|message| match message {
+                ServerMessage::EventRejected { reason, .. } => Some(*reason),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn update(&mut self, delta: Duration) {
+        if !self.connected {
+            return;
+        }
+        self.client.update(delta).unwrap();
+
+        while let Some(message) = self.client.receive_message(GAME_CHANNEL) {
+            let message: ServerMessage = bincode::deserialize(&message).unwrap();
+            match &message {
+                ServerMessage::Welcome {
+                    player_id,
+                    session_token,
+                } => {
+                    self.player_id = Some(*player_id);
+                    self.session_token = Some(*session_token);
+                }
+                ServerMessage::StateSnapshot(snapshot) => self.game_state = snapshot.clone(),
+                ServerMessage::GameEvent(event) => self.game_state.consume(event),
+                _ => {}
+            }
+            self.messages.push(message);
+        }
+
+        if self.client.is_connected() {
+            self.client.send_packets().unwrap();
+        }
+    }
+}
+
+/// A server and the clients connected to it
+pub struct Harness {
+    pub server: Server,
+    pub clients: Vec<TestClient>,
+    last_updated: Instant,
+}
+
+impl Harness {
+    /// Starts a server on a free port, playing by the given rules and keeping its history in memory
+    pub fn new(rules: Rules) -> Self {
+        let config = Config {
+            port: 0,
+            ..Config::default()
+        };
+        Self::with_config(&config, rules)
+    }
+
+    pub fn with_config(config: &Config, rules: Rules) -> Self {
+        let server = Server::bind(config, rules, Box::new(MemoryStorage::default())).unwrap();
+        Self {
+            server,
+            clients: Vec::new(),
+            last_updated: Instant::now(),
+        }
+    }
+
+    /// Connects a new client, returning its index in `clients`
+    pub fn connect(&mut self, name: &str, room: Option<&str>) -> usize {
+        let user_data = UserData {
+            name: name.to_string(),
+            room: room.map(String::from),
+            session_token: None,
+        };
+        let client = net::new_renet_client(self.server.addr(), &user_data).unwrap();
+        self.clients.push(TestClient {
+            client,
+            connected: true,
+            user_data,
+            player_id: None,
+            session_token: None,
+            game_state: GameState::default(),
+            messages: Vec::new(),
+        });
+        self.clients.len() - 1
+    }
+
+    /// Connects a client again after it has disconnected, using the session token it was handed
+    pub fn reconnect(&mut self, index: usize) {
+        let client = &mut self.clients[index];
+        let user_data = UserData {
+            session_token: client.session_token,
+            ..client.user_data.clone()
+        };
+        client.client = net::new_renet_client(self.server.addr(), &user_data).unwrap();
+        client.connected = true;
+    }
+
+    pub fn disconnect(&mut self, index: usize) {
+        let client = &mut self.clients[index];
+        client.client.disconnect();
+        client.connected = false;
+    }
+
+    pub fn send(&mut self, index: usize, message: ClientMessage) {
+        let message = bincode::serialize(&message).unwrap();
+        self.clients[index]
+            .client
+            .send_message(GAME_CHANNEL, message);
+    }
+
+    /// Updates the server and every connected client once
+    pub fn tick(&mut self) {
+        thread::sleep(TICK);
+        self.server.tick().unwrap();
+
+        let now = Instant::now();
+        for client in self.clients.iter_mut() {
+            client.update(now - self.last_updated);
+        }
+        self.last_updated = now;
+    }
+
+    /// Ticks until the condition holds, failing the test if it takes too long
+    pub fn run_until(&mut self, what: &str, done: impl Fn(&Harness) -> bool) {
+        let started_at = Instant::now();
+        while !done(self) {
+            if started_at.elapsed() > TIMEOUT {
+                panic!("Timed out waiting for {}", what);
+            }
+            self.tick();
+        }
+    }
+
+    /// Connects two clients and waits for their game to begin. Returns their indices in `clients`
+    pub fn start_game(&mut self, room: Option<&str>) -> (usize, usize) {
+        let alice = self.connect("alice", room);
+        self.run_until("alice to join", |h| h.clients[alice].player_id.is_some());
+        let bob = self.connect("bob", room);
+        self.run_until("the game to begin", |h| {
+            [alice, bob]
+                .iter()
+                .all(|i| h.clients[*i].game_state.stage == Stage::InGame)
+        });
+        (alice, bob)
+    }
+}
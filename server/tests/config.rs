@@ -0,0 +1,73 @@
+use server::config::Config;
+use std::time::Duration;
+use store::{Rules, Variant};
+
+#[test]
+fn builds_the_rules_from_the_config() {
+    assert_eq!(Config::default().rules().unwrap(), Rules::default());
+
+    let config = Config {
+        board_width: Some(15),
+        board_height: Some(15),
+        win_length: Some(5),
+        keep_sides: true,
+        move_seconds: Some(30),
+        ..Config::default()
+    };
+    let rules = config.rules().unwrap();
+    assert_eq!((rules.width, rules.height, rules.win_length), (15, 15, 5));
+    assert!(!rules.swap_sides_on_rematch);
+    assert_eq!(rules.time_control.per_move, Some(Duration::from_secs(30)));
+    assert_eq!(rules.time_control.per_game, None);
+
+    let config = Config {
+        variant: "ultimate".to_string(),
+        ..Config::default()
+    };
+    assert_eq!(config.rules().unwrap().variant, Variant::Ultimate);
+}
+
+#[test]
+fn refuses_rules_that_cannot_be_played() {
+    let unwinnable = Config {
+        win_length: Some(4),
+        ..Config::default()
+    };
+    assert!(unwinnable.rules().is_err());
+
+    let resized_ultimate = Config {
+        variant: "ultimate".to_string(),
+        board_width: Some(3),
+        ..Config::default()
+    };
+    assert!(resized_ultimate.rules().is_err());
+
+    let unknown_variant = Config {
+        variant: "quantum".to_string(),
+        ..Config::default()
+    };
+    assert!(unknown_variant.rules().is_err());
+}
+
+#[test]
+fn needs_an_address_clients_can_reach() {
+    assert!(Config::default().validate().is_ok());
+
+    let everywhere = Config {
+        host: "0.0.0.0".to_string(),
+        ..Config::default()
+    };
+    assert!(everywhere.validate().is_err());
+
+    let unreachable = Config {
+        public_addr: Some("0.0.0.0:5000".parse().unwrap()),
+        ..everywhere.clone()
+    };
+    assert!(unreachable.validate().is_err());
+
+    let reachable = Config {
+        public_addr: Some("192.0.2.1:5000".parse().unwrap()),
+        ..everywhere
+    };
+    assert!(reachable.validate().is_ok());
+}
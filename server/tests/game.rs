@@ -0,0 +1,332 @@
+mod common;
+
+use common::Harness;
+use server::config::Config;
+use std::net::TcpStream;
+use std::time::Duration;
+use store::net::{self, ConnectionRefused};
+use store::{
+    ClientMessage, EndGameReason, GameEvent, Rules, ServerMessage, Stage, Tile, TilePosition,
+    TimeControl, UserData, ValidationError,
+};
+
+/// Asks to place a piece on the tile at the given index of a classic board
+fn place(at: usize) -> ClientMessage {
+    ClientMessage::PlaceTile {
+        at: TilePosition::Board { at },
+    }
+}
+
+#[test]
+fn plays_a_full_game_to_a_win() {
+    let mut harness = Harness::new(Rules::default());
+    let (alice, bob) = harness.start_game(None);
+
+    // The player joining second goes first
+    let first = harness.clients[bob].player_id.unwrap();
+    assert_eq!(harness.clients[alice].game_state.active_player_id, first);
+
+    // Bob takes the bottom row while Alice plays above him
+    for (player, at) in [(bob, 0), (alice, 3), (bob, 1), (alice, 4), (bob, 2)] {
+        harness.send(player, place(at));
+        harness.run_until("the tile to be placed", |h| {
+            h.clients
+                .iter()
+                .all(|client| client.game_state.board[at] != Tile::Empty)
+        });
+    }
+    harness.run_until("the game to end", |h| {
+        h.clients
+            .iter()
+            .all(|client| client.game_state.stage == Stage::Ended)
+    });
+
+    for client in harness.clients.iter() {
+        let reason = client.events().find_map(|event| match event {
+            GameEvent::EndGame { reason } => Some(*reason),
+            _ => None,
+        });
+        assert_eq!(reason, Some(EndGameReason::PlayerWon { winner: first }));
+        assert!(client.rejections().is_empty());
+    }
+    // Both clients played along with the same events, so they should agree on how the game went
+    assert_eq!(
+        harness.clients[alice].game_state,
+        harness.clients[bob].game_state
+    );
+
+    // The match counts towards the leaderboard
+    harness.send(alice, ClientMessage::RequestLeaderboard);
+    harness.run_until("the leaderboard", |h| {
+        h.clients[alice]
+            .messages
+            .iter()
+            .any(|message| matches!(message, ServerMessage::Leaderboard(_)))
+    });
+    let leaderboard = harness.clients[alice]
+        .messages
+        .iter()
+        .find_map(|message| match message {
+            ServerMessage::Leaderboard(entries) => Some(entries.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(leaderboard[0].name, "bob");
+    assert_eq!(leaderboard[0].wins, 1);
+    assert_eq!(leaderboard[1].name, "alice");
+    assert_eq!(leaderboard[1].losses, 1);
+}
+
+#[test]
+fn rejects_invalid_moves() {
+    let mut harness = Harness::new(Rules::default());
+    let (alice, bob) = harness.start_game(None);
+
+    // It is Bob's turn, not Alice's
+    harness.send(alice, place(0));
+    harness.run_until("the move to be rejected", |h| {
+        !h.clients[alice].rejections().is_empty()
+    });
+
+    harness.send(bob, place(4));
+    harness.run_until("the tile to be placed", |h| {
+        h.clients[alice].game_state.board[4] != Tile::Empty
+    });
+    harness.send(alice, place(4));
+    harness.send(alice, place(9));
+    harness.run_until("the moves to be rejected", |h| {
+        h.clients[alice].rejections().len() == 3
+    });
+
+    assert_eq!(
+        harness.clients[alice].rejections(),
+        vec![
+            ValidationError::NotYourTurn,
+            ValidationError::TileOccupied,
+            ValidationError::OutOfBounds
+        ]
+    );
+    // Rejected moves are only sent back to the player that made them
+    assert!(harness.clients[bob].rejections().is_empty());
+    let placed = harness.clients[bob]
+        .events()
+        .filter(|event| matches!(event, GameEvent::PlaceTile { .. }))
+        .count();
+    assert_eq!(placed, 1);
+}
+
+#[test]
+fn takes_back_a_move_when_the_opponent_agrees() {
+    let mut harness = Harness::new(Rules::default());
+    let (alice, bob) = harness.start_game(None);
+
+    for (player, at) in [(bob, 0), (alice, 4)] {
+        harness.send(player, place(at));
+        harness.run_until("the tile to be placed", |h| {
+            h.clients[bob].game_state.board[at] != Tile::Empty
+        });
+    }
+
+    // Nobody has asked for a takeback yet, so there is nothing to accept
+    harness.send(bob, ClientMessage::AcceptTakeback);
+    harness.run_until("the accept to be rejected", |h| {
+        !h.clients[bob].rejections().is_empty()
+    });
+    assert_eq!(
+        harness.clients[bob].rejections(),
+        vec![ValidationError::NothingToAccept]
+    );
+
+    let alice_id = harness.clients[alice].player_id.unwrap();
+    harness.send(alice, ClientMessage::RequestTakeback);
+    harness.run_until("the opponent to hear about the request", |h| {
+        h.clients[bob].game_state.takeback_requested_by == Some(alice_id)
+    });
+    harness.send(bob, ClientMessage::AcceptTakeback);
+    harness.run_until("the move to be taken back", |h| {
+        h.clients
+            .iter()
+            .all(|client| client.game_state.board[4] == Tile::Empty)
+    });
+
+    // Only Alice's move is undone, and she gets to make it again
+    for client in harness.clients.iter() {
+        assert_ne!(client.game_state.board[0], Tile::Empty);
+        assert_eq!(client.game_state.active_player_id, alice_id);
+        assert_eq!(client.game_state.takeback_requested_by, None);
+    }
+    assert!(harness.clients[alice].rejections().is_empty());
+}
+
+#[test]
+fn ends_games_by_agreement_or_resignation() {
+    let mut harness = Harness::new(Rules::default());
+    let (alice, bob) = harness.start_game(None);
+
+    // Only one draw offer can be pending, and players can't take their own
+    harness.send(alice, ClientMessage::OfferDraw);
+    harness.send(alice, ClientMessage::OfferDraw);
+    harness.send(alice, ClientMessage::AcceptDraw);
+    harness.run_until("the offers to be rejected", |h| {
+        h.clients[alice].rejections().len() == 2
+    });
+    assert_eq!(
+        harness.clients[alice].rejections(),
+        vec![
+            ValidationError::AlreadyRequested,
+            ValidationError::NothingToAccept
+        ]
+    );
+
+    harness.send(bob, ClientMessage::AcceptDraw);
+    harness.run_until("the game to end in a draw", |h| {
+        h.clients
+            .iter()
+            .all(|client| client.game_state.stage == Stage::Ended)
+    });
+    assert!(harness.clients[alice].events().any(|event| *event
+        == GameEvent::EndGame {
+            reason: EndGameReason::DrawAgreed
+        }));
+
+    harness.send(alice, ClientMessage::RequestRematch);
+    harness.run_until("bob to hear about the rematch", |h| {
+        h.clients[bob].game_state.rematch_requested_by.is_some()
+    });
+    harness.send(bob, ClientMessage::AcceptRematch);
+    harness.run_until("the rematch to begin", |h| {
+        h.clients
+            .iter()
+            .all(|client| client.game_state.stage == Stage::InGame)
+    });
+
+    let bob_id = harness.clients[bob].player_id.unwrap();
+    harness.send(bob, ClientMessage::Resign);
+    harness.run_until("the game to end", |h| {
+        h.clients
+            .iter()
+            .all(|client| client.game_state.stage == Stage::Ended)
+    });
+    assert!(harness.clients[alice].events().any(|event| *event
+        == GameEvent::EndGame {
+            reason: EndGameReason::Resigned { player_id: bob_id }
+        }));
+}
+
+#[test]
+fn holds_the_seat_of_a_player_that_disconnects_mid_game() {
+    let mut harness = Harness::new(Rules::default());
+    let (alice, bob) = harness.start_game(None);
+    let bob_id = harness.clients[bob].player_id.unwrap();
+
+    harness.disconnect(bob);
+    harness.run_until("alice to hear that bob is gone", |h| {
+        h.clients[alice]
+            .events()
+            .any(|event| *event == GameEvent::PlayerConnectionLost { player_id: bob_id })
+    });
+    assert_eq!(harness.clients[alice].game_state.stage, Stage::InGame);
+    assert!(!harness.clients[alice].game_state.players[&bob_id].connected);
+
+    // Bob comes back to the seat he left and carries on with the game
+    harness.reconnect(bob);
+    harness.run_until("bob to reconnect", |h| {
+        h.clients[alice]
+            .events()
+            .any(|event| *event == GameEvent::PlayerReconnected { player_id: bob_id })
+    });
+    harness.send(bob, place(0));
+    harness.run_until("the tile to be placed", |h| {
+        h.clients
+            .iter()
+            .all(|client| client.game_state.board[0] != Tile::Empty)
+    });
+    assert_eq!(harness.clients[bob].player_id, Some(bob_id));
+    assert_eq!(
+        harness.clients[alice].game_state,
+        harness.clients[bob].game_state
+    );
+}
+
+#[test]
+fn lets_clients_watch_a_full_room() {
+    let mut harness = Harness::new(Rules::default());
+    let (_, bob) = harness.start_game(Some("arena"));
+
+    let carol = harness.connect("carol", Some("arena"));
+    harness.run_until("carol to start watching", |h| {
+        h.clients[carol].game_state.stage == Stage::InGame
+    });
+    assert_eq!(
+        harness.clients[carol].messages[0],
+        ServerMessage::Spectating
+    );
+    assert_eq!(harness.clients[carol].player_id, None);
+
+    // Spectators see the moves being made, but can't make any themselves
+    harness.send(carol, place(0));
+    harness.send(bob, place(8));
+    harness.run_until("the tile to be placed", |h| {
+        h.clients[carol].game_state.board[8] != Tile::Empty
+    });
+    assert_eq!(harness.clients[carol].game_state.board[0], Tile::Empty);
+}
+
+#[test]
+fn hands_out_tokens_while_another_connection_stays_silent() {
+    let mut harness = Harness::new(Rules::default());
+
+    // Someone opens a connection to the token issuer and never says anything
+    let _silent = TcpStream::connect(harness.server.addr()).unwrap();
+
+    let (alice, bob) = harness.start_game(None);
+    assert!(harness.clients[alice].player_id.is_some());
+    assert!(harness.clients[bob].player_id.is_some());
+}
+
+#[test]
+fn refuses_clients_speaking_another_protocol() {
+    let config = Config {
+        port: 0,
+        protocol_id: net::PROTOCOL_ID + 1,
+        ..Config::default()
+    };
+    let harness = Harness::with_config(&config, Rules::default());
+
+    let user_data = UserData {
+        name: "alice".to_string(),
+        room: None,
+        session_token: None,
+    };
+    let err = net::new_renet_client(harness.server.addr(), &user_data).unwrap_err();
+    assert!(err.downcast_ref::<ConnectionRefused>().is_some());
+}
+
+#[test]
+fn only_takes_time_off_the_clock_for_the_whole_game() {
+    let spent_time = |per_game: Option<Duration>| {
+        let rules = Rules {
+            time_control: TimeControl {
+                per_move: Some(Duration::from_secs(60)),
+                per_game,
+            },
+            ..Rules::default()
+        };
+        let mut harness = Harness::new(rules);
+        let (alice, bob) = harness.start_game(None);
+        harness.send(bob, place(4));
+        harness.run_until("the tile to be placed", |h| {
+            h.clients[alice].game_state.board[4] != Tile::Empty
+        });
+
+        harness.clients[alice]
+            .game_state
+            .history
+            .iter()
+            .any(|event| matches!(event, GameEvent::SpendTime { .. }))
+    };
+
+    // The time for a single move starts over with every move, so there is nothing to keep track of
+    assert!(!spent_time(None));
+    assert!(spent_time(Some(Duration::from_secs(600))));
+}
@@ -0,0 +1,94 @@
+use server::storage::{FileStorage, MatchRecord, MatchStorage, MemoryStorage, Outcome};
+use std::path::PathBuf;
+
+fn record(winner: &str) -> MatchRecord {
+    match_between("alice", "bob", winner)
+}
+
+fn match_between(a: &str, b: &str, winner: &str) -> MatchRecord {
+    MatchRecord {
+        players: vec![a.to_string(), b.to_string()],
+        outcome: Outcome::Won {
+            winner: winner.to_string(),
+        },
+        started_at: 0,
+        ended_at: 1,
+    }
+}
+
+/// A file of its own for every test, so tests running at the same time don't share one
+fn history_path(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "tictactussle-{}-{}.jsonl",
+        test,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn skips_a_match_that_was_cut_off_while_being_written() {
+    let path = history_path("cut-off");
+    let written = serde_json::to_string(&record("alice")).unwrap();
+    let cut_off = serde_json::to_string(&record("bob")).unwrap();
+    std::fs::write(
+        &path,
+        format!("{}\n{}", written, &cut_off[..cut_off.len() / 2]),
+    )
+    .unwrap();
+
+    let mut storage = FileStorage::open(&path).unwrap();
+    assert_eq!(storage.matches_of("alice").unwrap(), vec![record("alice")]);
+
+    // Matches recorded afterwards don't end up on the line that was cut off
+    storage.record_match(record("bob")).unwrap();
+    let storage = FileStorage::open(&path).unwrap();
+    assert_eq!(
+        storage.matches_of("bob").unwrap(),
+        vec![record("alice"), record("bob")]
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn refuses_a_history_that_is_unreadable_in_the_middle() {
+    let path = history_path("unreadable");
+    let written = serde_json::to_string(&record("alice")).unwrap();
+    std::fs::write(&path, format!("not a match\n{}\n", written)).unwrap();
+
+    assert!(FileStorage::open(&path).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn lists_players_with_the_same_rating_by_name() {
+    let mut storage = MemoryStorage::default();
+    storage
+        .record_match(match_between("dave", "carol", "dave"))
+        .unwrap();
+    storage
+        .record_match(match_between("bob", "alice", "bob"))
+        .unwrap();
+
+    let names: Vec<String> = storage
+        .leaderboard(4)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["bob", "dave", "alice", "carol"]);
+}
+
+#[test]
+fn leaves_the_rating_alone_when_a_player_meets_their_namesake() {
+    let mut storage = MemoryStorage::default();
+    let initial_rating = storage.rating("alice").unwrap();
+    storage
+        .record_match(match_between("alice", "alice", "alice"))
+        .unwrap();
+
+    assert_eq!(storage.rating("alice").unwrap(), initial_rating);
+}
@@ -0,0 +1,305 @@
+use crate::{GameEvent, GameState, PlayerId, Rules, Tile, TilePosition, Variant};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Score of a won position. Wins found deeper in the search score a little lower,
+// so the computer goes for the quickest win and puts off losing for as long as possible
+const WIN_SCORE: i32 = 1000;
+
+// Boards with more empty tiles than this are too big to search all the way to the end
+const FULL_SEARCH_LIMIT: usize = 12;
+// How many moves ahead to look on boards too big to search all the way to the end
+const MAX_SEARCH_DEPTH: usize = 4;
+
+// Score of claiming one of the small boards of ultimate tic-tac-toe
+const CLAIM_SCORE: i32 = 10;
+
+/// How well a computer controlled opponent plays
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    /// Plays a random tile most of the time
+    Easy,
+    /// Plays well, but every now and then makes a blunder
+    Medium,
+    /// Never blunders. On a classic 3x3 board it can't be beaten
+    Hard,
+}
+
+impl Difficulty {
+    /// The chance that the computer plays a random tile instead of the best one
+    fn blunder_chance(&self) -> f64 {
+        match self {
+            Difficulty::Easy => 0.6,
+            Difficulty::Medium => 0.25,
+            Difficulty::Hard => 0.0,
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "easy" => Ok(Difficulty::Easy),
+            "medium" => Ok(Difficulty::Medium),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!(
+                "'{}' is not a difficulty, pick easy, medium or hard",
+                s
+            )),
+        }
+    }
+}
+
+/// Picks the tile the given player should place a piece on next.
+/// Returns None if the player isn't in the game or if there are no empty tiles left.
+pub fn choose_move(
+    game_state: &GameState,
+    player_id: PlayerId,
+    difficulty: Difficulty,
+) -> Option<TilePosition> {
+    if game_state.rules.variant == Variant::Ultimate {
+        return choose_ultimate_move(game_state, player_id, difficulty);
+    }
+
+    let me = game_state.get_player_tile(&player_id)?;
+    let them = match me {
+        Tile::Tic => Tile::Tac,
+        Tile::Tac => Tile::Tic,
+        Tile::Empty => return None,
+    };
+
+    let mut rng = rand::thread_rng();
+    let mut board = game_state.board.clone();
+    let moves = candidate_moves(&board, &game_state.rules);
+    if rng.gen_bool(difficulty.blunder_chance()) {
+        return moves
+            .choose(&mut rng)
+            .map(|at| TilePosition::Board { at: *at });
+    }
+
+    let empty_tiles = board.iter().filter(|tile| **tile == Tile::Empty).count();
+    let max_depth = if empty_tiles <= FULL_SEARCH_LIMIT {
+        empty_tiles
+    } else {
+        MAX_SEARCH_DEPTH
+    };
+
+    let mut best: Option<(usize, i32)> = None;
+    for at in moves {
+        board[at] = me;
+        let score = score_move(
+            &mut board,
+            &game_state.rules,
+            at,
+            me,
+            them,
+            1,
+            max_depth,
+            -WIN_SCORE,
+            WIN_SCORE,
+        );
+        board[at] = Tile::Empty;
+
+        if best
+            .map(|(_, best_score)| score > best_score)
+            .unwrap_or(true)
+        {
+            best = Some((at, score));
+        }
+    }
+
+    best.map(|(at, _)| TilePosition::Board { at })
+}
+
+/// Ultimate tic-tac-toe is too big to search like the classic game, so the computer only looks one move ahead.
+/// It goes for moves that win the game or claim a small board, and stays away from moves that send the opponent
+/// somewhere they can do the same.
+fn choose_ultimate_move(
+    game_state: &GameState,
+    player_id: PlayerId,
+    difficulty: Difficulty,
+) -> Option<TilePosition> {
+    let opponent_id = *game_state.players.keys().find(|id| **id != player_id)?;
+
+    // Trying out moves doesn't need the history, and it is a lot cheaper to copy the game without it
+    let mut game_state = game_state.clone();
+    game_state.history.clear();
+
+    let mut rng = rand::thread_rng();
+    let mut moves = playable_tiles(&game_state);
+    if rng.gen_bool(difficulty.blunder_chance()) {
+        return moves.choose(&mut rng).copied();
+    }
+    // Shuffle the moves so the computer doesn't always pick the first of the equally good ones
+    moves.shuffle(&mut rng);
+
+    let mut best: Option<(TilePosition, i32)> = None;
+    for at in moves {
+        let (after, gain) = try_move(&game_state, player_id, at);
+        let score = if gain == WIN_SCORE {
+            WIN_SCORE
+        } else {
+            let best_reply = playable_tiles(&after)
+                .into_iter()
+                .map(|reply| try_move(&after, opponent_id, reply).1)
+                .max()
+                .unwrap_or(0);
+            gain - best_reply
+        };
+
+        if best
+            .map(|(_, best_score)| score > best_score)
+            .unwrap_or(true)
+        {
+            best = Some((at, score));
+        }
+    }
+
+    best.map(|(at, _)| at)
+}
+
+/// Plays a move on a copy of the game. Returns the game after the move along with what the move gained the player,
+/// which is `WIN_SCORE` for winning the game and `CLAIM_SCORE` for claiming a small board
+fn try_move(game_state: &GameState, player_id: PlayerId, at: TilePosition) -> (GameState, i32) {
+    let claimed = |game_state: &GameState| {
+        game_state
+            .sub_boards
+            .iter()
+            .filter(|board| board.winner != Tile::Empty)
+            .count()
+    };
+
+    let mut after = game_state.clone();
+    after.consume(&GameEvent::PlaceTile { player_id, at });
+    let gain = if after.determine_winner() == Some(player_id) {
+        WIN_SCORE
+    } else if claimed(&after) > claimed(game_state) {
+        CLAIM_SCORE
+    } else {
+        0
+    };
+
+    (after, gain)
+}
+
+/// The tiles the active player can place a piece on
+fn playable_tiles(game_state: &GameState) -> Vec<TilePosition> {
+    game_state
+        .rules
+        .positions()
+        .into_iter()
+        .filter(|at| game_state.can_place_at(*at))
+        .collect()
+}
+
+/// Scores the move that was just made at `at` from the perspective of the player who made it.
+/// This is minimax in its negamax form: the best score for one player is the worst for the other.
+#[allow(clippy::too_many_arguments)]
+fn score_move(
+    board: &mut [Tile],
+    rules: &Rules,
+    at: usize,
+    just_moved: Tile,
+    to_move: Tile,
+    depth: usize,
+    max_depth: usize,
+    alpha: i32,
+    beta: i32,
+) -> i32 {
+    if rules.completes_line(board, at) {
+        return WIN_SCORE - depth as i32;
+    }
+
+    // A full board is a draw, and when we can't look any further we call it even
+    if depth >= max_depth || board.iter().all(|tile| *tile != Tile::Empty) {
+        return 0;
+    }
+
+    -negamax(
+        board, rules, to_move, just_moved, depth, max_depth, -beta, -alpha,
+    )
+}
+
+/// Finds the best score `me` can get from the current board. Alpha-beta pruning skips
+/// lines of play that can't change the outcome, since a better option has already been found.
+#[allow(clippy::too_many_arguments)]
+fn negamax(
+    board: &mut [Tile],
+    rules: &Rules,
+    me: Tile,
+    them: Tile,
+    depth: usize,
+    max_depth: usize,
+    mut alpha: i32,
+    beta: i32,
+) -> i32 {
+    let mut best = -WIN_SCORE;
+    for at in candidate_moves(board, rules) {
+        board[at] = me;
+        let score = score_move(
+            board,
+            rules,
+            at,
+            me,
+            them,
+            depth + 1,
+            max_depth,
+            alpha,
+            beta,
+        );
+        board[at] = Tile::Empty;
+
+        best = best.max(score);
+        alpha = alpha.max(score);
+        if alpha >= beta {
+            break;
+        }
+    }
+
+    best
+}
+
+/// The tiles worth considering placing a piece on.
+/// On small boards that is every empty tile. On big boards it is only the empty tiles
+/// next to a piece, since tiles far away from the action are rarely good moves.
+fn candidate_moves(board: &[Tile], rules: &Rules) -> Vec<usize> {
+    let empty = (0..board.len()).filter(|at| board[*at] == Tile::Empty);
+    if board.len() <= 16 {
+        return empty.collect();
+    }
+
+    let has_neighbour = |at: usize| {
+        let (x, y) = rules.coordinates(at);
+        (-1..=1).any(|dx| {
+            (-1..=1).any(|dy| {
+                rules
+                    .index(x as isize + dx, y as isize + dy)
+                    .map(|next| board[next] != Tile::Empty)
+                    .unwrap_or(false)
+            })
+        })
+    };
+
+    let empty: Vec<usize> = empty.collect();
+    if empty.len() == board.len() {
+        // Nothing has been played yet, so start in the middle
+        return vec![rules
+            .index(rules.width as isize / 2, rules.height as isize / 2)
+            .unwrap()];
+    }
+
+    let moves: Vec<usize> = empty
+        .iter()
+        .copied()
+        .filter(|at| has_neighbour(*at))
+        .collect();
+    if moves.is_empty() {
+        return empty;
+    }
+
+    moves
+}
@@ -0,0 +1,59 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+/// The host the server listens on and the clients connect to, unless told otherwise
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+/// The port the server listens on and the clients connect to, unless told otherwise
+pub const DEFAULT_PORT: u16 = 5000;
+
+/// Settings shared by the graphical and the terminal client. Anything passed on the command line wins.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    /// The name to play as
+    pub name: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            name: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// The address of the server to connect to
+    pub fn server_addr(&self) -> anyhow::Result<SocketAddr> {
+        resolve_addr(&self.host, self.port)
+    }
+}
+
+/// Reads a toml config file. Settings missing from the file keep their defaults,
+/// and without a file at all every setting does.
+pub fn read_config<T: DeserializeOwned + Default>(path: Option<&Path>) -> anyhow::Result<T> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(T::default()),
+    };
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("Could not read config file {}: {}", path.display(), err))?;
+    toml::from_str(&contents)
+        .map_err(|err| anyhow::anyhow!("Invalid config file {}: {}", path.display(), err))
+}
+
+/// Looks up the address of a host, which may be a name like "localhost" or an ip address
+pub fn resolve_addr(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|err| anyhow::anyhow!("Could not resolve host '{}': {}", host, err))?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Host '{}' has no addresses", host))
+}
@@ -1,5 +1,11 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub mod ai;
+pub mod config;
+pub mod net;
+pub mod replay;
 
 /// Struct for storing player related data.
 /// In tic-tac-toe the only thing we need is the name and the piece the player will be placing
@@ -7,6 +13,11 @@ use std::collections::HashMap;
 pub struct Player {
     pub name: String,
     pub piece: Tile,
+    /// Whether the player is connected. Players that lose their connection mid game
+    /// keep their place in it for a while, so they can reconnect.
+    pub connected: bool,
+    /// How much time the player has left for the rest of the game, if the game is played with a time limit
+    pub time_left: Option<Duration>,
 }
 
 /// Possible GameStates for a tile in the board
@@ -26,37 +37,262 @@ pub enum Stage {
 }
 
 // This just makes it easier to dissern between a player id and any ol' u64
-type PlayerId = u64;
+pub type PlayerId = u64;
+
+/// A secret handed to a player when they join, which they can use to reconnect to the game
+pub type SessionToken = u64;
+
+/// How long the server holds on to the place of a disconnected player before they forfeit the game
+pub const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Limits on how long players get to think. A player that runs out of time loses the game
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeControl {
+    /// How long a player has for a single move
+    pub per_move: Option<Duration>,
+    /// How long a player has for all of their moves in a game put together
+    pub per_game: Option<Duration>,
+}
+
+/// The kinds of tic-tac-toe that can be played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Variant {
+    /// The first to get `win_length` tiles in a row anywhere on the board wins
+    Classic,
+    /// The 9x9 board is made up of nine small 3x3 boards. Getting three in a row on a small board claims it,
+    /// and the first to claim three small boards in a row wins. The tile a player picks within a small board
+    /// decides which small board their opponent has to play in next.
+    Ultimate,
+}
+
+/// Where on the board a piece is placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TilePosition {
+    /// A tile of a classic board, counting row by row from the bottom left
+    Board { at: usize },
+    /// A tile of one of the small boards of ultimate tic-tac-toe. Both the small board
+    /// and the tile within it count row by row from the bottom left, like tiles do.
+    SubBoard { sub_board: usize, cell: usize },
+}
+
+/// One of the small boards of ultimate tic-tac-toe
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubBoard {
+    pub tiles: Vec<Tile>,
+    /// Whoever claimed the small board by getting three in a row on it, if anyone has
+    pub winner: Tile,
+}
+
+impl Default for SubBoard {
+    fn default() -> Self {
+        Self {
+            tiles: vec![Tile::Empty; SUB_BOARD_RULES.tile_count()],
+            winner: Tile::Empty,
+        }
+    }
+}
+
+impl SubBoard {
+    /// Determines whether the small board is out of play, because it has been claimed or filled up
+    pub fn is_closed(&self) -> bool {
+        self.winner != Tile::Empty || self.tiles.iter().all(|tile| *tile != Tile::Empty)
+    }
+}
+
+/// How many tiles there are along each side of the small boards in ultimate tic-tac-toe,
+/// and how many small boards there are along each side of the big board
+pub const SUB_BOARD_SIZE: usize = 3;
+
+/// The rules the small boards of ultimate tic-tac-toe are played by, and the big board made up of them
+const SUB_BOARD_RULES: Rules = Rules {
+    width: SUB_BOARD_SIZE,
+    height: SUB_BOARD_SIZE,
+    win_length: SUB_BOARD_SIZE,
+    swap_sides_on_rematch: true,
+    time_control: TimeControl {
+        per_move: None,
+        per_game: None,
+    },
+    variant: Variant::Classic,
+};
+
+/// The shape of the board and how many tiles in a row it takes to win.
+/// Classic tic-tac-toe is a 3x3 board with 3 in a row, gomoku is a 15x15 board with 5 in a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    pub width: usize,
+    pub height: usize,
+    pub win_length: usize,
+    /// Whether players swap pieces and who goes first when they play a rematch
+    pub swap_sides_on_rematch: bool,
+    pub time_control: TimeControl,
+    pub variant: Variant,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            width: 3,
+            height: 3,
+            win_length: 3,
+            swap_sides_on_rematch: true,
+            time_control: TimeControl::default(),
+            variant: Variant::Classic,
+        }
+    }
+}
+
+impl Rules {
+    /// The rules of ultimate tic-tac-toe, see [`Variant::Ultimate`]
+    pub fn ultimate() -> Self {
+        Self {
+            width: SUB_BOARD_SIZE * SUB_BOARD_SIZE,
+            height: SUB_BOARD_SIZE * SUB_BOARD_SIZE,
+            win_length: SUB_BOARD_SIZE,
+            variant: Variant::Ultimate,
+            ..Self::default()
+        }
+    }
+
+    /// Determines whether it is possible to win a game played by these rules
+    pub fn is_playable(&self) -> bool {
+        if self.variant == Variant::Ultimate {
+            let ultimate = Self::ultimate();
+            return (self.width, self.height, self.win_length)
+                == (ultimate.width, ultimate.height, ultimate.win_length);
+        }
+
+        self.width > 0
+            && self.height > 0
+            && self.win_length > 0
+            && self.win_length <= self.width.max(self.height)
+    }
+
+    /// The number of tiles on the board
+    pub fn tile_count(&self) -> usize {
+        self.width * self.height
+    }
+
+    /// Converts a tile index into (x, y) coordinates on the board
+    pub fn coordinates(&self, at: usize) -> (usize, usize) {
+        (at % self.width, at / self.width)
+    }
+
+    /// Converts (x, y) coordinates into a tile index, if the coordinates are on the board
+    pub fn index(&self, x: isize, y: isize) -> Option<usize> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+
+        Some(x as usize + y as usize * self.width)
+    }
+
+    /// Every tile on the board
+    pub fn positions(&self) -> Vec<TilePosition> {
+        match self.variant {
+            Variant::Classic => (0..self.tile_count())
+                .map(|at| TilePosition::Board { at })
+                .collect(),
+            Variant::Ultimate => (0..SUB_BOARD_RULES.tile_count())
+                .flat_map(|sub_board| {
+                    (0..SUB_BOARD_RULES.tile_count())
+                        .map(move |cell| TilePosition::SubBoard { sub_board, cell })
+                })
+                .collect(),
+        }
+    }
+
+    /// The tile at the given (x, y) coordinates, if the coordinates are on the board.
+    /// The board of ultimate tic-tac-toe is laid out as a grid of small boards.
+    pub fn position(&self, x: isize, y: isize) -> Option<TilePosition> {
+        let at = self.index(x, y)?;
+        Some(match self.variant {
+            Variant::Classic => TilePosition::Board { at },
+            Variant::Ultimate => {
+                let size = SUB_BOARD_SIZE as isize;
+                TilePosition::SubBoard {
+                    sub_board: SUB_BOARD_RULES.index(x / size, y / size).unwrap(),
+                    cell: SUB_BOARD_RULES.index(x % size, y % size).unwrap(),
+                }
+            }
+        })
+    }
+
+    /// Converts a tile into (x, y) coordinates on the board
+    pub fn position_coordinates(&self, position: TilePosition) -> (usize, usize) {
+        match position {
+            TilePosition::Board { at } => self.coordinates(at),
+            TilePosition::SubBoard { sub_board, cell } => {
+                let (board_x, board_y) = SUB_BOARD_RULES.coordinates(sub_board);
+                let (cell_x, cell_y) = SUB_BOARD_RULES.coordinates(cell);
+                (
+                    board_x * SUB_BOARD_SIZE + cell_x,
+                    board_y * SUB_BOARD_SIZE + cell_y,
+                )
+            }
+        }
+    }
+
+    /// The piece that has a line long enough to win the game on the board, if any
+    pub fn winning_piece(&self, board: &[Tile]) -> Option<Tile> {
+        (0..board.len())
+            .find(|at| self.completes_line(board, *at))
+            .map(|at| board[at])
+    }
+
+    /// Determines if the tile at the given index is part of a line long enough to win the game
+    pub fn completes_line(&self, board: &[Tile], at: usize) -> bool {
+        let tile = board[at];
+        if tile == Tile::Empty {
+            return false;
+        }
+
+        let (x, y) = self.coordinates(at);
+        // Counts the equal tiles next to the given tile in one direction
+        let count = |dx: isize, dy: isize| {
+            (1..self.win_length as isize)
+                .take_while(|step| {
+                    self.index(x as isize + dx * step, y as isize + dy * step)
+                        .map(|next| board[next] == tile)
+                        .unwrap_or(false)
+                })
+                .count()
+        };
+
+        [(1, 0), (0, 1), (1, 1), (1, -1)]
+            .iter()
+            .any(|(dx, dy)| 1 + count(*dx, *dy) + count(-dx, -dy) >= self.win_length)
+    }
+}
 
 /// A GameState object that is able to keep track of a game of TicTacTussle
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GameState {
     pub stage: Stage,
-    pub board: [Tile; 9],
+    pub rules: Rules,
+    /// The tiles of a classic board. Ultimate tic-tac-toe keeps its tiles in `sub_boards` instead
+    pub board: Vec<Tile>,
     pub active_player_id: PlayerId,
-    pub players: HashMap<PlayerId, Player>,
+    /// The player that placed the first tile of the current game
+    pub first_player_id: PlayerId,
+    /// The player asking for a rematch once the game has ended, if anyone has
+    pub rematch_requested_by: Option<PlayerId>,
+    /// The player asking to take back their last move, if anyone is
+    pub takeback_requested_by: Option<PlayerId>,
+    /// The player offering to call the game a draw, if anyone is
+    pub draw_offered_by: Option<PlayerId>,
+    /// The small boards of ultimate tic-tac-toe. Empty in the other variants
+    pub sub_boards: Vec<SubBoard>,
+    /// The small board the active player has to play in next, or None if they may play in any of them
+    pub forced_sub_board: Option<usize>,
+    /// Players are kept in a sorted map, so the state serializes the same way on every machine
+    pub players: BTreeMap<PlayerId, Player>,
     pub history: Vec<GameEvent>,
 }
 
 impl Default for GameState {
     fn default() -> Self {
-        Self {
-            stage: Stage::PreGame,
-            board: [
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-                Tile::Empty,
-            ],
-            active_player_id: 0,
-            players: HashMap::new(),
-            history: Vec::new(),
-        }
+        Self::new(Rules::default())
     }
 }
 
@@ -67,66 +303,561 @@ pub enum EndGameReason {
     // Note that it might make sense to keep playing in some other game (like Team Fight Tactics for instance).
     PlayerLeft { player_id: PlayerId },
     PlayerWon { winner: PlayerId },
+    // The board filled up without anyone getting enough tiles in a row
+    Draw,
+    // The player ran out of time, handing the win to their opponent
+    Timeout { player_id: PlayerId },
+    // The player gave up, handing the win to their opponent
+    Resigned { player_id: PlayerId },
+    // Both players agreed to call it a draw before the board filled up
+    DrawAgreed,
 }
 
 /// An event that progresses the GameGameState forward
 #[derive(Debug, Clone, Serialize, PartialEq, Deserialize)]
 pub enum GameEvent {
-    BeginGame { goes_first: PlayerId },
-    EndGame { reason: EndGameReason },
-    PlayerJoined { player_id: PlayerId, name: String },
-    PlayerDisconnected { player_id: PlayerId },
-    PlaceTile { player_id: PlayerId, at: usize },
+    BeginGame {
+        goes_first: PlayerId,
+    },
+    EndGame {
+        reason: EndGameReason,
+    },
+    PlayerJoined {
+        player_id: PlayerId,
+        name: String,
+    },
+    PlayerDisconnected {
+        player_id: PlayerId,
+    },
+    PlayerConnectionLost {
+        player_id: PlayerId,
+    },
+    PlayerReconnected {
+        player_id: PlayerId,
+    },
+    PlaceTile {
+        player_id: PlayerId,
+        at: TilePosition,
+    },
+    /// Sent by the server right before a move, taking the time the player spent on it off their clock
+    SpendTime {
+        player_id: PlayerId,
+        elapsed: Duration,
+    },
+    RequestRematch {
+        player_id: PlayerId,
+    },
+    AcceptRematch {
+        player_id: PlayerId,
+    },
+    /// A player asks their opponent to let them take back their last move
+    RequestTakeback {
+        player_id: PlayerId,
+    },
+    /// The opponent lets the player take back their last move, along with any move made after it
+    AcceptTakeback {
+        player_id: PlayerId,
+    },
+    /// The opponent wants the game to go on as it is
+    DeclineTakeback {
+        player_id: PlayerId,
+    },
+    /// A player gives up, ending the game
+    Resign {
+        player_id: PlayerId,
+    },
+    /// A player offers to end the game in a draw. The offer stands until the next move is made
+    OfferDraw {
+        player_id: PlayerId,
+    },
+    /// The opponent takes the draw that was offered, ending the game
+    AcceptDraw {
+        player_id: PlayerId,
+    },
+}
+
+/// The reasons an event can be rejected by [`GameState::validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValidationError {
+    /// The event concerns a player that is not part of the game
+    UnknownPlayer,
+    /// A player with the same id has already joined the game
+    DuplicatePlayer,
+    /// The event is not allowed in the stage the game is currently in
+    WrongStage,
+    /// A player tried to place a tile while it was the other players turn
+    NotYourTurn,
+    /// A player tried to place a tile outside of the board
+    OutOfBounds,
+    /// A player tried to place a tile on top of another tile
+    TileOccupied,
+    /// A player asked for something they are already waiting for an answer to
+    AlreadyRequested,
+    /// A player tried to accept a request that nobody has made
+    NothingToAccept,
+    /// A player tried to decline a request that nobody has made
+    NothingToDecline,
+    /// A player asked to take back a move before making one
+    NothingToTakeBack,
+    /// A player tried to place a tile outside of the small board they were sent to
+    WrongSubBoard,
+    /// A player tried to place a tile on a small board that has already been claimed
+    SubBoardClaimed,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ValidationError::*;
+        let message = match self {
+            UnknownPlayer => "That player is not part of this game",
+            DuplicatePlayer => "That player has already joined the game",
+            WrongStage => "That can't be done right now",
+            NotYourTurn => "It's not your turn",
+            OutOfBounds => "That tile is outside of the board",
+            TileOccupied => "That tile is already taken",
+            AlreadyRequested => "You are already waiting for an answer",
+            NothingToAccept => "There is nothing to accept",
+            NothingToDecline => "There is nothing to decline",
+            NothingToTakeBack => "You have no moves to take back",
+            WrongSubBoard => "You have to play in the highlighted board",
+            SubBoardClaimed => "That board has already been won",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Data a client hands to the server when connecting. It is carried in renet's user data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserData {
+    pub name: String,
+    /// The named room the client wants to play in. Clients without a room are paired up
+    /// with whoever else is waiting for an opponent.
+    pub room: Option<String>,
+    /// The session token handed out by the server when the client first joined.
+    /// Clients present it when reconnecting to get their old place in the game back.
+    pub session_token: Option<SessionToken>,
+}
+
+impl UserData {
+    /// Encodes the user data as bytes prefixed with their length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let data = bincode::serialize(self).unwrap();
+        let mut bytes = (data.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(data);
+        bytes
+    }
+
+    /// Decodes user data written by `to_bytes`. Returns None if the bytes are malformed
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut buffer = [0u8; 8];
+        buffer.copy_from_slice(bytes.get(0..8)?);
+        let len = u64::from_le_bytes(buffer) as usize;
+        let data = bytes.get(8..8usize.checked_add(len)?)?;
+        bincode::deserialize(data).ok()
+    }
+}
+
+/// A message sent from a client to the server.
+/// Clients only get to ask for the things a player can do. Which player is asking is decided by the
+/// server from the connection the message arrived on, so a client can't act on behalf of anybody else.
+#[derive(Debug, Clone, Serialize, PartialEq, Deserialize)]
+pub enum ClientMessage {
+    /// Place a piece on the given tile
+    PlaceTile { at: TilePosition },
+    /// Ask the opponent to play again once the game has ended
+    RequestRematch,
+    /// Agree to play again with the opponent that asked for it
+    AcceptRematch,
+    /// Ask the opponent to let us take back our last move
+    RequestTakeback,
+    /// Let the opponent take back their last move
+    AcceptTakeback,
+    /// Refuse to let the opponent take back their last move
+    DeclineTakeback,
+    /// Give up the game
+    Resign,
+    /// Offer the opponent to end the game in a draw
+    OfferDraw,
+    /// Take the draw the opponent offered
+    AcceptDraw,
+    /// Asks the server for a snapshot of the game, because the clients state has drifted from it
+    RequestResync,
+    /// Asks the server for the best rated players
+    RequestLeaderboard,
+}
+
+impl ClientMessage {
+    /// The game event a message from the given player asks for.
+    /// Returns None if the message isn't about playing the game.
+    pub fn into_event(self, player_id: PlayerId) -> Option<GameEvent> {
+        match self {
+            ClientMessage::PlaceTile { at } => Some(GameEvent::PlaceTile { player_id, at }),
+            ClientMessage::RequestRematch => Some(GameEvent::RequestRematch { player_id }),
+            ClientMessage::AcceptRematch => Some(GameEvent::AcceptRematch { player_id }),
+            ClientMessage::RequestTakeback => Some(GameEvent::RequestTakeback { player_id }),
+            ClientMessage::AcceptTakeback => Some(GameEvent::AcceptTakeback { player_id }),
+            ClientMessage::DeclineTakeback => Some(GameEvent::DeclineTakeback { player_id }),
+            ClientMessage::Resign => Some(GameEvent::Resign { player_id }),
+            ClientMessage::OfferDraw => Some(GameEvent::OfferDraw { player_id }),
+            ClientMessage::AcceptDraw => Some(GameEvent::AcceptDraw { player_id }),
+            ClientMessage::RequestResync | ClientMessage::RequestLeaderboard => None,
+        }
+    }
+}
+
+/// The longest chat message the server passes on, in characters
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+/// A line of chat between the players in a room. Chat is sent over a channel of its own, see `net::CHAT_CHANNEL`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// The name of whoever wrote the message, or None for messages from the server itself.
+    /// Clients leave it empty, the server fills it in from the player sitting in the seat of the connection.
+    pub sender: Option<String>,
+    pub text: String,
+}
+
+/// A line on the leaderboard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub name: String,
+    /// The Elo rating of the player. Everyone starts out at 1200
+    pub rating: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+/// A message sent from the server to a client
+#[derive(Debug, Clone, Serialize, PartialEq, Deserialize)]
+pub enum ServerMessage {
+    /// Sent to a client when it connects, so it knows who it is in the game it has joined.
+    /// A snapshot of the game follows, so the client can catch up.
+    Welcome {
+        player_id: PlayerId,
+        session_token: SessionToken,
+    },
+    /// Sent instead of Welcome to a client that joined a room which already has two players.
+    /// It gets to watch the game, but can't take part in it. A snapshot of the game follows.
+    Spectating,
+    /// The entire state of the game. Clients replace whatever state they had with it
+    StateSnapshot(GameState),
+    /// Sent every now and then so clients can check that their state matches the one on the server
+    StateChecksum(u64),
+    /// The best rated players on the server, best first
+    Leaderboard(Vec<LeaderboardEntry>),
+    /// A valid event that every client should consume
+    GameEvent(GameEvent),
+    /// The event the client sent was rejected by the server
+    EventRejected {
+        event: GameEvent,
+        reason: ValidationError,
+    },
 }
 
 impl GameState {
+    /// Creates a new game with an empty board shaped by the given rules
+    pub fn new(rules: Rules) -> Self {
+        Self {
+            stage: Stage::PreGame,
+            rules,
+            board: empty_board(rules),
+            active_player_id: 0,
+            first_player_id: 0,
+            rematch_requested_by: None,
+            takeback_requested_by: None,
+            draw_offered_by: None,
+            sub_boards: empty_sub_boards(rules),
+            forced_sub_board: None,
+            players: BTreeMap::new(),
+            history: Vec::new(),
+        }
+    }
+
+    /// Rebuilds a game by playing the given events on a new game with the given rules.
+    /// Fails with the reason the first invalid event was rejected.
+    pub fn from_events(rules: Rules, events: &[GameEvent]) -> Result<Self, ValidationError> {
+        let mut game_state = Self::new(rules);
+        game_state.play_history(events)?;
+        Ok(game_state)
+    }
+
+    /// The state the game was in once the first `n` events of its history had been played.
+    /// Asking for more events than there are gives back the game as it is now.
+    pub fn state_at(&self, n: usize) -> Result<Self, ValidationError> {
+        Self::from_events(self.rules, &self.history[..n.min(self.history.len())])
+    }
+
+    /// Determines whether playing the history on a new game ends up in this very state.
+    /// A state that isn't consistent has been changed in a way its history doesn't account for.
+    pub fn is_consistent(&self) -> bool {
+        Self::from_events(self.rules, &self.history).as_ref() == Ok(self)
+    }
+
+    /// Validates and consumes events taken from a history, one after the other.
+    /// Unlike `apply` nothing is derived from them, since a history already holds every consequence of its events.
+    pub fn play_history(&mut self, events: &[GameEvent]) -> Result<(), ValidationError> {
+        for event in events {
+            self.validate(event)?;
+            self.consume(event);
+        }
+
+        Ok(())
+    }
+
     /// Determines whether an event is valid considering the current GameState
-    pub fn validate(&self, event: &GameEvent) -> bool {
+    pub fn validate(&self, event: &GameEvent) -> Result<(), ValidationError> {
         use GameEvent::*;
         match event {
             BeginGame { goes_first } => {
-                let player_is_unknown = self.players.contains_key(goes_first);
-                if self.stage != Stage::PreGame || player_is_unknown {
-                    return false;
+                if self.stage != Stage::PreGame {
+                    return Err(ValidationError::WrongStage);
+                }
+                if !self.players.contains_key(goes_first) {
+                    return Err(ValidationError::UnknownPlayer);
                 }
             }
             EndGame { reason } => match reason {
-                EndGameReason::PlayerWon { winner: _ } => {
+                EndGameReason::PlayerWon { winner: _ }
+                | EndGameReason::Draw
+                | EndGameReason::Resigned { player_id: _ }
+                | EndGameReason::DrawAgreed => {
                     if self.stage != Stage::InGame {
-                        return false;
+                        return Err(ValidationError::WrongStage);
+                    }
+                }
+                EndGameReason::Timeout { player_id } => {
+                    if !self.players.contains_key(player_id) {
+                        return Err(ValidationError::UnknownPlayer);
+                    }
+
+                    if self.stage != Stage::InGame {
+                        return Err(ValidationError::WrongStage);
+                    }
+                }
+                // The player has already been removed by the time their leaving ends the game,
+                // so there is nobody left to check for, only that there was a game to end
+                EndGameReason::PlayerLeft { player_id: _ } => {
+                    if self.stage != Stage::InGame {
+                        return Err(ValidationError::WrongStage);
                     }
                 }
-                _ => {}
             },
             PlayerJoined { player_id, name: _ } => {
                 if self.players.contains_key(player_id) {
-                    return false;
+                    return Err(ValidationError::DuplicatePlayer);
                 }
             }
-            PlayerDisconnected { player_id } => {
+            PlayerDisconnected { player_id }
+            | PlayerConnectionLost { player_id }
+            | PlayerReconnected { player_id } => {
                 if !self.players.contains_key(player_id) {
-                    return false;
+                    return Err(ValidationError::UnknownPlayer);
+                }
+            }
+            SpendTime {
+                player_id,
+                elapsed: _,
+            } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
                 }
             }
             PlaceTile { player_id, at } => {
                 if !self.players.contains_key(player_id) {
-                    return false;
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
                 }
 
                 if self.active_player_id != *player_id {
-                    return false;
+                    return Err(ValidationError::NotYourTurn);
+                }
+
+                self.check_tile(*at)?;
+            }
+            RequestRematch { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
                 }
 
-                if *at > 8 {
-                    return false;
+                // A rematch needs both players to still be around after the game has ended
+                if self.stage != Stage::Ended || self.players.len() != 2 {
+                    return Err(ValidationError::WrongStage);
                 }
-                if self.board[*at] != Tile::Empty {
-                    return false;
+
+                if self.rematch_requested_by.is_some() {
+                    return Err(ValidationError::AlreadyRequested);
+                }
+            }
+            AcceptRematch { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::Ended || self.players.len() != 2 {
+                    return Err(ValidationError::WrongStage);
+                }
+
+                // Players can only accept rematches their opponent asked for
+                match self.rematch_requested_by {
+                    Some(requested_by) if requested_by != *player_id => {}
+                    _ => return Err(ValidationError::NothingToAccept),
+                }
+            }
+            RequestTakeback { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
+                }
+
+                if self.takeback_requested_by.is_some() {
+                    return Err(ValidationError::AlreadyRequested);
+                }
+
+                if !self.moves().iter().any(|(id, _)| id == player_id) {
+                    return Err(ValidationError::NothingToTakeBack);
+                }
+            }
+            AcceptTakeback { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
+                }
+
+                // Players can only accept takebacks their opponent asked for
+                match self.takeback_requested_by {
+                    Some(requested_by) if requested_by != *player_id => {}
+                    _ => return Err(ValidationError::NothingToAccept),
+                }
+            }
+            DeclineTakeback { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
+                }
+
+                match self.takeback_requested_by {
+                    Some(requested_by) if requested_by != *player_id => {}
+                    _ => return Err(ValidationError::NothingToDecline),
+                }
+            }
+            Resign { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
                 }
             }
+            OfferDraw { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
+                }
+
+                // Only one offer can be on the table at a time
+                if self.draw_offered_by.is_some() {
+                    return Err(ValidationError::AlreadyRequested);
+                }
+            }
+            AcceptDraw { player_id } => {
+                if !self.players.contains_key(player_id) {
+                    return Err(ValidationError::UnknownPlayer);
+                }
+
+                if self.stage != Stage::InGame {
+                    return Err(ValidationError::WrongStage);
+                }
+
+                // Players can only accept draws their opponent offered
+                match self.draw_offered_by {
+                    Some(offered_by) if offered_by != *player_id => {}
+                    _ => return Err(ValidationError::NothingToAccept),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates an event and consumes it along with everything that follows from it,
+    /// like the game ending when a tile completes a line.
+    /// Returns the event followed by its consequences, in the order they were consumed,
+    /// so they can be passed on to anyone else keeping track of the game.
+    pub fn apply(&mut self, event: GameEvent) -> Result<Vec<GameEvent>, ValidationError> {
+        self.validate(&event)?;
+
+        let mut events = Vec::new();
+        let mut next = Some(event);
+        while let Some(event) = next {
+            self.consume(&event);
+            next = self.follow_up(&event);
+            events.push(event);
         }
 
-        true
+        Ok(events)
+    }
+
+    /// The event that follows from the given event having just been consumed, if any
+    fn follow_up(&self, event: &GameEvent) -> Option<GameEvent> {
+        use GameEvent::*;
+        let reason = match event {
+            // In TicTacTussle the game can begin once two players has joined.
+            // The player that joined last gets to go first.
+            PlayerJoined { player_id, name: _ }
+                if self.stage == Stage::PreGame && self.players.len() == 2 =>
+            {
+                return Some(BeginGame {
+                    goes_first: *player_id,
+                });
+            }
+            _ if self.stage != Stage::InGame => return None,
+            PlaceTile { .. } => match self.determine_winner() {
+                Some(winner) => EndGameReason::PlayerWon { winner },
+                None if self.is_draw() => EndGameReason::Draw,
+                None => return None,
+            },
+            // Tic tac toe can't go on with a single player
+            PlayerDisconnected { player_id } => EndGameReason::PlayerLeft {
+                player_id: *player_id,
+            },
+            Resign { player_id } => EndGameReason::Resigned {
+                player_id: *player_id,
+            },
+            AcceptDraw { player_id: _ } => EndGameReason::DrawAgreed,
+            SpendTime { player_id, .. }
+                if self.time_left_for_move(player_id, Duration::ZERO) == Some(Duration::ZERO) =>
+            {
+                EndGameReason::Timeout {
+                    player_id: *player_id,
+                }
+            }
+            _ => return None,
+        };
+
+        Some(GameEvent::EndGame { reason })
     }
 
     /// Consumes an event, modifying the GameState and adding the event to its history
@@ -136,9 +867,17 @@ impl GameState {
         match valid_event {
             BeginGame { goes_first } => {
                 self.active_player_id = *goes_first;
+                self.first_player_id = *goes_first;
                 self.stage = Stage::InGame;
+                self.takeback_requested_by = None;
+                self.draw_offered_by = None;
+                self.reset_clocks();
+            }
+            EndGame { reason: _ } => {
+                self.stage = Stage::Ended;
+                self.takeback_requested_by = None;
+                self.draw_offered_by = None;
             }
-            EndGame { reason: _ } => self.stage = Stage::Ended,
             PlayerJoined { player_id, name } => {
                 self.players.insert(
                     *player_id,
@@ -150,15 +889,29 @@ impl GameState {
                         } else {
                             Tile::Tic
                         },
+                        connected: true,
+                        time_left: self.rules.time_control.per_game,
                     },
                 );
             }
             PlayerDisconnected { player_id } => {
                 self.players.remove(player_id);
+                self.rematch_requested_by = None;
+                self.takeback_requested_by = None;
+                self.draw_offered_by = None;
+            }
+            PlayerConnectionLost { player_id } => {
+                self.players.get_mut(player_id).unwrap().connected = false;
+            }
+            PlayerReconnected { player_id } => {
+                self.players.get_mut(player_id).unwrap().connected = true;
             }
             PlaceTile { player_id, at } => {
-                let piece = self.get_player_tile(player_id).unwrap();
-                self.board[*at] = piece;
+                self.place_tile(player_id, *at);
+                // Moving on means the player is happy with how the game is going,
+                // and that the opponent would rather play on than take the draw they were offered
+                self.takeback_requested_by = None;
+                self.draw_offered_by = None;
                 self.active_player_id = self
                     .players
                     .keys()
@@ -166,11 +919,188 @@ impl GameState {
                     .unwrap()
                     .clone();
             }
+            SpendTime { player_id, elapsed } => {
+                let player = self.players.get_mut(player_id).unwrap();
+                player.time_left = player
+                    .time_left
+                    .map(|time_left| time_left.saturating_sub(*elapsed));
+            }
+            RequestRematch { player_id } => {
+                self.rematch_requested_by = Some(*player_id);
+            }
+            AcceptRematch { player_id: _ } => {
+                self.clear_board();
+                self.rematch_requested_by = None;
+                self.takeback_requested_by = None;
+                self.draw_offered_by = None;
+
+                if self.rules.swap_sides_on_rematch {
+                    for player in self.players.values_mut() {
+                        player.piece = match player.piece {
+                            Tile::Tic => Tile::Tac,
+                            Tile::Tac => Tile::Tic,
+                            Tile::Empty => Tile::Empty,
+                        };
+                    }
+                    self.first_player_id = *self
+                        .players
+                        .keys()
+                        .find(|id| **id != self.first_player_id)
+                        .unwrap();
+                }
+
+                self.active_player_id = self.first_player_id;
+                self.stage = Stage::InGame;
+                self.reset_clocks();
+            }
+            RequestTakeback { player_id } => {
+                self.takeback_requested_by = Some(*player_id);
+            }
+            AcceptTakeback { player_id: _ } => {
+                let requested_by = self.takeback_requested_by.take().unwrap();
+                let mut moves = self.moves();
+                take_back(&mut moves, requested_by);
+
+                // Rebuild the board from the moves that are left, and hand the turn back to the player
+                self.clear_board();
+                for (player_id, at) in moves {
+                    self.place_tile(&player_id, at);
+                }
+                self.active_player_id = requested_by;
+            }
+            DeclineTakeback { player_id: _ } => {
+                self.takeback_requested_by = None;
+            }
+            // The game ending is all there is to resigning, which follows from it
+            Resign { player_id: _ } => {}
+            OfferDraw { player_id } => {
+                self.draw_offered_by = Some(*player_id);
+            }
+            AcceptDraw { player_id: _ } => {
+                self.draw_offered_by = None;
+            }
         }
 
         self.history.push(valid_event.clone());
     }
 
+    /// The moves made in the current game, in the order they were made, leaving out any that were taken back.
+    /// They are rebuilt from the history, since the board alone doesn't tell which tile was placed last.
+    fn moves(&self) -> Vec<(PlayerId, TilePosition)> {
+        let mut moves = Vec::new();
+        let mut takeback_requested_by = None;
+        for event in &self.history {
+            match event {
+                GameEvent::BeginGame { .. } | GameEvent::AcceptRematch { .. } => moves.clear(),
+                GameEvent::PlaceTile { player_id, at } => moves.push((*player_id, *at)),
+                GameEvent::RequestTakeback { player_id } => {
+                    takeback_requested_by = Some(*player_id)
+                }
+                GameEvent::AcceptTakeback { .. } => {
+                    if let Some(requested_by) = takeback_requested_by {
+                        take_back(&mut moves, requested_by);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        moves
+    }
+
+    /// Places the piece of the player on the board, claiming the small board it completes a line on
+    /// and sending the opponent to the small board matching the tile, when playing ultimate tic-tac-toe
+    fn place_tile(&mut self, player_id: &PlayerId, at: TilePosition) {
+        let piece = self.get_player_tile(player_id).unwrap();
+        match at {
+            TilePosition::Board { at } => self.board[at] = piece,
+            TilePosition::SubBoard { sub_board, cell } => {
+                let board = &mut self.sub_boards[sub_board];
+                board.tiles[cell] = piece;
+                if board.winner == Tile::Empty && SUB_BOARD_RULES.completes_line(&board.tiles, cell)
+                {
+                    board.winner = piece;
+                }
+
+                // A small board that is out of play can't be sent to, so the opponent gets to pick any board instead
+                self.forced_sub_board =
+                    Some(cell).filter(|next| !self.sub_boards[*next].is_closed());
+            }
+        }
+    }
+
+    /// Empties the board for a new game
+    fn clear_board(&mut self) {
+        self.board = empty_board(self.rules);
+        self.sub_boards = empty_sub_boards(self.rules);
+        self.forced_sub_board = None;
+    }
+
+    /// The piece on the given tile, or None if the tile isn't on the board
+    pub fn tile(&self, at: TilePosition) -> Option<Tile> {
+        match at {
+            TilePosition::Board { at } => self.board.get(at).copied(),
+            TilePosition::SubBoard { sub_board, cell } => {
+                self.sub_boards.get(sub_board)?.tiles.get(cell).copied()
+            }
+        }
+    }
+
+    /// The number of pieces on the board
+    pub fn piece_count(&self) -> usize {
+        self.rules
+            .positions()
+            .into_iter()
+            .filter(|at| self.tile(*at) != Some(Tile::Empty))
+            .count()
+    }
+
+    /// Checks that a piece can be placed on the given tile, whoever's turn it is
+    fn check_tile(&self, at: TilePosition) -> Result<(), ValidationError> {
+        match self.tile(at) {
+            None => return Err(ValidationError::OutOfBounds),
+            Some(Tile::Empty) => {}
+            Some(_) => return Err(ValidationError::TileOccupied),
+        }
+
+        if let TilePosition::SubBoard { sub_board, cell: _ } = at {
+            if matches!(self.forced_sub_board, Some(forced) if forced != sub_board) {
+                return Err(ValidationError::WrongSubBoard);
+            }
+            if self.sub_boards[sub_board].winner != Tile::Empty {
+                return Err(ValidationError::SubBoardClaimed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Determines whether the active player could place a piece on the given tile
+    pub fn can_place_at(&self, at: TilePosition) -> bool {
+        self.check_tile(at).is_ok()
+    }
+
+    /// Gives every player the full time for a new game
+    fn reset_clocks(&mut self) {
+        for player in self.players.values_mut() {
+            player.time_left = self.rules.time_control.per_game;
+        }
+    }
+
+    /// How much time a player has left to make their move, given how long they have been thinking about it.
+    /// That is whichever runs out first of the time for the move and the time for the rest of the game.
+    /// Returns None if the game is played without time limits.
+    pub fn time_left_for_move(&self, player_id: &PlayerId, elapsed: Duration) -> Option<Duration> {
+        let for_move = self.rules.time_control.per_move;
+        let for_game = self.players.get(player_id)?.time_left;
+        let time_left = match (for_move, for_game) {
+            (Some(for_move), Some(for_game)) => for_move.min(for_game),
+            (time_left, None) | (None, time_left) => time_left?,
+        };
+
+        Some(time_left.saturating_sub(elapsed))
+    }
+
     /// Gets a players tile, if the player is known to game state
     pub fn get_player_tile(&self, player_id: &PlayerId) -> Option<Tile> {
         if let Some(player) = self.players.get(player_id) {
@@ -182,37 +1112,68 @@ impl GameState {
 
     /// Determines if someone has won the game
     pub fn determine_winner(&self) -> Option<PlayerId> {
-        // All the combinations of 3 tiles that wins the game
-        let row1: [usize; 3] = [0, 1, 2];
-        let row2: [usize; 3] = [3, 4, 5];
-        let row3: [usize; 3] = [6, 7, 8];
-        let col1: [usize; 3] = [0, 3, 6];
-        let col2: [usize; 3] = [1, 4, 7];
-        let col3: [usize; 3] = [2, 5, 8];
-        let diag1: [usize; 3] = [0, 4, 8];
-        let diag2: [usize; 3] = [2, 4, 6];
-
-        for arr in [row1, row2, row3, col1, col2, col3, diag1, diag2] {
-            // Read tiles from board
-            let tiles: [Tile; 3] = [self.board[arr[0]], self.board[arr[1]], self.board[arr[2]]];
-            // Determine if tiles are all equal
-            let all_are_the_same = tiles
-                .get(0)
-                .map(|first| tiles.iter().all(|x| x == first))
-                .unwrap_or(true);
-
-            if all_are_the_same {
-                // Determine which of the players won
-                if let Some((winner, _)) = self
-                    .players
-                    .iter()
-                    .find(|(_, player)| player.piece == self.board[arr[0]])
-                {
-                    return Some(*winner);
-                }
+        let piece = match self.rules.variant {
+            Variant::Classic => self.rules.winning_piece(&self.board),
+            // Ultimate tic-tac-toe is won on the big board made up of the claimed small boards
+            Variant::Ultimate => {
+                let claims: Vec<Tile> = self.sub_boards.iter().map(|board| board.winner).collect();
+                SUB_BOARD_RULES.winning_piece(&claims)
             }
-        }
+        };
 
-        None
+        piece.and_then(|piece| self.player_with_tile(piece))
+    }
+
+    /// Finds the player placing the given tile
+    fn player_with_tile(&self, tile: Tile) -> Option<PlayerId> {
+        self.players
+            .iter()
+            .find(|(_, player)| player.piece == tile)
+            .map(|(player_id, _)| *player_id)
+    }
+
+    /// Determines if the game is a draw, meaning there is nowhere left to place a piece and nobody has won
+    pub fn is_draw(&self) -> bool {
+        let board_is_full = self
+            .rules
+            .positions()
+            .into_iter()
+            .all(|at| !self.can_place_at(at));
+        board_is_full && self.determine_winner().is_none()
+    }
+
+    /// Computes a checksum of the entire state. Two states with the same checksum are practically
+    /// guaranteed to be the same, which makes it cheap to check that a client agrees with the server.
+    pub fn checksum(&self) -> u64 {
+        // FNV-1a is simple and stable across machines and rust versions, unlike the std hasher
+        bincode::serialize(self)
+            .unwrap()
+            .iter()
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+}
+
+/// The tiles of a new classic board. Ultimate tic-tac-toe has none, as its tiles are on the small boards
+fn empty_board(rules: Rules) -> Vec<Tile> {
+    match rules.variant {
+        Variant::Classic => vec![Tile::Empty; rules.tile_count()],
+        Variant::Ultimate => Vec::new(),
+    }
+}
+
+/// The small boards of a new game, nine of them in ultimate tic-tac-toe and none otherwise
+fn empty_sub_boards(rules: Rules) -> Vec<SubBoard> {
+    match rules.variant {
+        Variant::Classic => Vec::new(),
+        Variant::Ultimate => vec![SubBoard::default(); SUB_BOARD_RULES.tile_count()],
+    }
+}
+
+/// Removes the last move of the given player from the moves, along with every move made after it
+fn take_back(moves: &mut Vec<(PlayerId, TilePosition)>, player_id: PlayerId) {
+    if let Some(last_move) = moves.iter().rposition(|(id, _)| *id == player_id) {
+        moves.truncate(last_move);
     }
 }
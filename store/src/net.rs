@@ -0,0 +1,272 @@
+use crate::{
+    ClientMessage, GameState, PlayerId, ServerMessage, SessionToken, Stage, UserData,
+    RECONNECT_GRACE_PERIOD,
+};
+use renet::{
+    ChannelConfig, ClientAuthentication, ConnectToken, ReliableChannelConfig, RenetClient,
+    RenetConnectionConfig, NETCODE_USER_DATA_BYTES,
+};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Instant, SystemTime};
+
+// TicTacTussle converted to utf-8 codes is 84 105 99 84 97 99 84 117 115 115 108 101
+// If you add those up you get 1208.
+// It is not necessary to do the protocol id like this but it is fun 🤷‍♂️
+pub const PROTOCOL_ID: u64 = 1208;
+
+/// The version of the messages sent between the server and the clients.
+/// Messages are encoded with bincode, which has no way of telling that the other side
+/// encodes them differently, so it is checked before the client gets to connect.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 5, minor: 0 };
+
+// Answers the token issuer gives a client asking for a connect token
+pub const TOKEN_ISSUED: u8 = 0;
+pub const CONNECTION_REFUSED: u8 = 1;
+
+/// Bump the major version whenever a change to the messages breaks older peers, like adding a
+/// variant to an enum the server sends or changing the fields of a message.
+/// Bump the minor version when the server learns to understand something new from clients,
+/// so older clients can keep playing on newer servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    /// Determines whether a server speaking this version understands a client speaking the given version
+    pub fn accepts(&self, client: ProtocolVersion) -> bool {
+        self.major == client.major && self.minor >= client.minor
+    }
+
+    pub fn to_bytes(self) -> [u8; 4] {
+        let [a, b] = self.major.to_le_bytes();
+        let [c, d] = self.minor.to_le_bytes();
+        [a, b, c, d]
+    }
+
+    pub fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self {
+            major: u16::from_le_bytes([bytes[0], bytes[1]]),
+            minor: u16::from_le_bytes([bytes[2], bytes[3]]),
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The server turned the client away, usually because they speak different versions of the protocol.
+/// Holds a reason that can be shown to the player as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionRefused(pub String);
+
+impl std::fmt::Display for ConnectionRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConnectionRefused {}
+
+/// The channel carrying `ClientMessage`s and `ServerMessage`s
+pub const GAME_CHANNEL: u8 = 0;
+/// The channel carrying `ChatMessage`s. Chat has a channel of its own,
+/// so a burst of chatter can't hold up the messages that keep the game going.
+/// Channels 1 and 2 are the unreliable and chunk channels renet sets up by default.
+pub const CHAT_CHANNEL: u8 = 3;
+
+/// The connection configuration used by both the server and the clients. They have to agree on the channels.
+pub fn connection_config() -> RenetConnectionConfig {
+    let chat_channel = || {
+        ChannelConfig::Reliable(ReliableChannelConfig {
+            channel_id: CHAT_CHANNEL,
+            ..Default::default()
+        })
+    };
+
+    let mut config = RenetConnectionConfig::default();
+    config.send_channels_config.push(chat_channel());
+    config.receive_channels_config.push(chat_channel());
+    config
+}
+
+/// Connects to the server at the given address.
+/// Every client connects the same way, so they all look alike to the server.
+/// Fails with `ConnectionRefused` if the server won't have us.
+pub fn new_renet_client(
+    server_addr: SocketAddr,
+    user_data: &UserData,
+) -> anyhow::Result<RenetClient> {
+    // Listen on every interface of the same kind as the servers address, so remote servers can be reached too
+    let local_addr: SocketAddr = match server_addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local_addr)?;
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let (client_id, connect_token) = fetch_connect_token(server_addr, user_data)?;
+
+    let client = RenetClient::new(
+        current_time,
+        socket,
+        client_id,
+        connection_config(),
+        ClientAuthentication::Secure { connect_token },
+    )?;
+
+    Ok(client)
+}
+
+/// Asks the token issuer running alongside the server for a connect token.
+/// The token carries our user data, so the server knows who we are when we connect.
+///
+/// The client sends the protocol id and version it speaks, followed by its user data.
+/// The issuer answers with `TOKEN_ISSUED`, the client id and the token,
+/// or with `CONNECTION_REFUSED` and a length prefixed reason.
+fn fetch_connect_token(
+    server_addr: SocketAddr,
+    user_data: &UserData,
+) -> anyhow::Result<(u64, ConnectToken)> {
+    let bytes = user_data.to_bytes();
+    if bytes.len() > NETCODE_USER_DATA_BYTES {
+        anyhow::bail!("Username or room name is too big");
+    }
+
+    // The token issuer listens for TCP connections on the same address the server uses for UDP
+    let mut stream = TcpStream::connect(server_addr)?;
+    stream.write_all(&PROTOCOL_ID.to_le_bytes())?;
+    stream.write_all(&PROTOCOL_VERSION.to_bytes())?;
+    stream.write_all(&bytes)?;
+
+    let mut answer = [0u8; 1];
+    stream.read_exact(&mut answer)?;
+    if answer[0] == CONNECTION_REFUSED {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let mut reason = vec![0u8; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut reason)?;
+        return Err(ConnectionRefused(String::from_utf8_lossy(&reason).into_owned()).into());
+    }
+
+    let mut client_id = [0u8; 8];
+    stream.read_exact(&mut client_id)?;
+    let connect_token = ConnectToken::read(&mut stream)?;
+
+    Ok((u64::from_le_bytes(client_id), connect_token))
+}
+
+/// Who we are in the game, handed to us by the server when we join
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub player_id: PlayerId,
+    pub session_token: SessionToken,
+}
+
+/// What every client keeps track of about its connection to the server.
+/// The clients only differ in how they show the game, so they leave talking to the server to this.
+#[derive(Debug, Clone)]
+pub struct ClientSession {
+    pub server_addr: SocketAddr,
+    pub user_data: UserData,
+    /// Who we are in the game, once the server has welcomed us. Spectators never get one
+    pub session: Option<Session>,
+    pub spectating: bool,
+    awaiting_resync: bool,
+    disconnected_at: Option<Instant>,
+}
+
+impl ClientSession {
+    pub fn new(server_addr: SocketAddr, user_data: UserData) -> Self {
+        Self {
+            server_addr,
+            user_data,
+            session: None,
+            spectating: false,
+            awaiting_resync: false,
+            disconnected_at: None,
+        }
+    }
+
+    /// Connects to the server for the first time
+    pub fn connect(&self) -> anyhow::Result<RenetClient> {
+        new_renet_client(self.server_addr, &self.user_data)
+    }
+
+    /// Keeps the game in line with a message from the server.
+    /// Returns the messages the server should get in answer to it.
+    pub fn handle(
+        &mut self,
+        game_state: &mut GameState,
+        message: &ServerMessage,
+    ) -> Vec<ClientMessage> {
+        match message {
+            ServerMessage::Welcome {
+                player_id,
+                session_token,
+            } => {
+                self.session = Some(Session {
+                    player_id: *player_id,
+                    session_token: *session_token,
+                });
+                // Give the player something to look at while waiting for an opponent
+                return vec![ClientMessage::RequestLeaderboard];
+            }
+            ServerMessage::Spectating => self.spectating = true,
+            ServerMessage::StateSnapshot(snapshot) => {
+                // Throw away whatever we had and pick up from where the server is
+                *game_state = snapshot.clone();
+                self.awaiting_resync = false;
+            }
+            ServerMessage::StateChecksum(checksum) => {
+                // If we don't agree with the server on the state of the game, something got lost along the way.
+                // Ask for a snapshot, but only once, since more checksums will arrive before it does.
+                if *checksum != game_state.checksum() && !self.awaiting_resync {
+                    self.awaiting_resync = true;
+                    return vec![ClientMessage::RequestResync];
+                }
+            }
+            ServerMessage::GameEvent(event) => {
+                // We trust the server - It's always been good to us!
+                // No need to validate the events it is sending us
+                game_state.consume(event);
+            }
+            ServerMessage::Leaderboard(_) | ServerMessage::EventRejected { .. } => {}
+        }
+
+        Vec::new()
+    }
+
+    /// Lets the session know the client is connected, so it can tell how long any later disconnect lasts
+    pub fn mark_connected(&mut self) {
+        self.disconnected_at = None;
+    }
+
+    /// Decides how to get back into the game after losing the connection, which is by connecting
+    /// again with the returned user data. It carries our session token, so the server gives us our old place back.
+    /// Fails if there is no place to get back to, or if the server has given it away by now.
+    pub fn reconnect_as(
+        &mut self,
+        game_state: &GameState,
+        err: impl std::fmt::Display,
+    ) -> anyhow::Result<UserData> {
+        let session_token = match &self.session {
+            Some(session) if game_state.stage == Stage::InGame => session.session_token,
+            _ => anyhow::bail!("{}", err),
+        };
+
+        let now = Instant::now();
+        if now - *self.disconnected_at.get_or_insert(now) > RECONNECT_GRACE_PERIOD {
+            anyhow::bail!("Could not reconnect to the server: {}", err);
+        }
+
+        Ok(UserData {
+            session_token: Some(session_token),
+            ..self.user_data.clone()
+        })
+    }
+}
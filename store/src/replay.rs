@@ -0,0 +1,125 @@
+use crate::{GameEvent, GameState, PlayerId, ValidationError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The version of the replay format. Bump it whenever the format changes,
+/// so old replays are rejected instead of being played back wrong.
+pub const REPLAY_VERSION: u32 = 5;
+
+/// A recording of a single match, from the moment it began until it ended
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    /// The names of the players taking part in the match
+    pub players: BTreeMap<PlayerId, String>,
+    /// When the match began and ended, in seconds since the unix epoch
+    pub started_at: u64,
+    pub ended_at: u64,
+    /// The state of the game right before the match began
+    pub initial_state: GameState,
+    /// Every event played during the match, in order
+    pub events: Vec<GameEvent>,
+}
+
+/// Reasons a replay file can't be played back
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u32),
+    /// One of the recorded events can't be played where it was recorded
+    InvalidEvent(ValidationError),
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "Could not read the replay: {}", err),
+            ReplayError::Malformed(err) => write!(f, "The replay is malformed: {}", err),
+            ReplayError::UnsupportedVersion(version) => write!(
+                f,
+                "The replay is version {}, but only version {} can be played",
+                version, REPLAY_VERSION
+            ),
+            ReplayError::InvalidEvent(reason) => {
+                write!(f, "The replay can't be played back: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+impl Replay {
+    /// Starts recording a match that is about to begin in the given game
+    pub fn record(game_state: &GameState) -> Self {
+        // The history of earlier matches isn't needed to play this one back
+        let mut initial_state = game_state.clone();
+        initial_state.history.clear();
+
+        Self {
+            version: REPLAY_VERSION,
+            players: game_state
+                .players
+                .iter()
+                .map(|(player_id, player)| (*player_id, player.name.clone()))
+                .collect(),
+            started_at: unix_time(),
+            ended_at: 0,
+            initial_state,
+            events: Vec::new(),
+        }
+    }
+
+    /// Marks the recording as done
+    pub fn finish(&mut self) {
+        self.ended_at = unix_time();
+    }
+
+    /// The state of the game after the given number of events has been played
+    pub fn state_at(&self, step: usize) -> Result<GameState, ValidationError> {
+        let mut game_state = self.initial_state.clone();
+        game_state.play_history(&self.events[..step.min(self.events.len())])?;
+        Ok(game_state)
+    }
+
+    /// Writes the replay to a new file. Fails with `AlreadyExists` rather than overwrite another replay
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(json.as_bytes())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ReplayError> {
+        let json = std::fs::read_to_string(path).map_err(ReplayError::Io)?;
+
+        // Check the version on its own first, since older versions might not parse as a replay at all
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        let Version { version } = serde_json::from_str(&json).map_err(ReplayError::Malformed)?;
+        if version != REPLAY_VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+
+        let replay: Self = serde_json::from_str(&json).map_err(ReplayError::Malformed)?;
+        // Make sure the whole match can be played back, so every step of it can be shown
+        replay
+            .state_at(replay.events.len())
+            .map_err(ReplayError::InvalidEvent)?;
+
+        Ok(replay)
+    }
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::{new_game, ALICE, BOB};
+use store::ai::{choose_move, Difficulty};
+use store::{EndGameReason, GameEvent, GameState, PlayerId, Rules, Stage, TilePosition};
+
+/// A new game where Bob goes first, after the given moves have been played on the tiles of a classic board
+fn game_after(rules: Rules, moves: &[(PlayerId, usize)]) -> GameState {
+    let mut game_state = new_game(rules);
+    for (player_id, at) in moves {
+        let event = GameEvent::PlaceTile {
+            player_id: *player_id,
+            at: TilePosition::Board { at: *at },
+        };
+        game_state.apply(event).unwrap();
+    }
+
+    game_state
+}
+
+/// Lets the computer play both sides until the game ends, returning how it ended
+fn play_out(mut game_state: GameState, difficulty: Difficulty) -> EndGameReason {
+    while game_state.stage == Stage::InGame {
+        let player_id = game_state.active_player_id;
+        let at = choose_move(&game_state, player_id, difficulty).unwrap();
+        assert!(game_state.can_place_at(at), "{:?} can't be played", at);
+        game_state
+            .apply(GameEvent::PlaceTile { player_id, at })
+            .unwrap();
+    }
+
+    match game_state.history.last() {
+        Some(GameEvent::EndGame { reason }) => *reason,
+        event => panic!("The game ended without an EndGame event, got {:?}", event),
+    }
+}
+
+#[test]
+fn hard_takes_an_immediate_win() {
+    // Both have two in a row, and it is Bob's turn to finish his
+    let game_state = game_after(
+        Rules::default(),
+        &[(BOB, 0), (ALICE, 3), (BOB, 1), (ALICE, 4)],
+    );
+    assert_eq!(
+        choose_move(&game_state, BOB, Difficulty::Hard),
+        Some(TilePosition::Board { at: 2 })
+    );
+}
+
+#[test]
+fn hard_blocks_an_immediate_loss() {
+    // Alice is about to complete the diagonal from the top left, and Bob has no win of his own
+    let game_state = game_after(
+        Rules::default(),
+        &[(BOB, 0), (ALICE, 4), (BOB, 8), (ALICE, 2)],
+    );
+    assert_eq!(
+        choose_move(&game_state, BOB, Difficulty::Hard),
+        Some(TilePosition::Board { at: 6 })
+    );
+}
+
+#[test]
+fn hard_draws_against_itself_on_a_classic_board() {
+    let game_state = game_after(Rules::default(), &[]);
+    assert_eq!(play_out(game_state, Difficulty::Hard), EndGameReason::Draw);
+}
+
+#[test]
+fn only_picks_playable_tiles_in_ultimate_tic_tac_toe() {
+    // Medium blunders now and then, so the computer gets to pick from all sorts of positions
+    for _ in 0..3 {
+        let game_state = game_after(Rules::ultimate(), &[]);
+        play_out(game_state, Difficulty::Medium);
+    }
+}
@@ -0,0 +1,20 @@
+//! Games the store tests start from.
+
+use store::{GameEvent, GameState, PlayerId, Rules};
+
+pub const ALICE: PlayerId = 1;
+pub const BOB: PlayerId = 2;
+
+/// A game with the given rules that Alice and Bob have joined, so it has begun and it is Bob's turn
+pub fn new_game(rules: Rules) -> GameState {
+    let mut game_state = GameState::new(rules);
+    for (player_id, name) in [(ALICE, "alice"), (BOB, "bob")] {
+        let event = GameEvent::PlayerJoined {
+            player_id,
+            name: name.to_string(),
+        };
+        game_state.apply(event).unwrap();
+    }
+
+    game_state
+}
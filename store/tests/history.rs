@@ -0,0 +1,140 @@
+use store::{
+    EndGameReason, GameEvent, GameState, Rules, Stage, Tile, TilePosition, ValidationError,
+};
+
+const ALICE: u64 = 1;
+const BOB: u64 = 2;
+
+/// A game where Bob takes a move back and goes on to win on the bottom row
+fn played_game() -> GameState {
+    let mut game_state = GameState::new(Rules::default());
+    let events = [
+        GameEvent::PlayerJoined {
+            player_id: ALICE,
+            name: "alice".to_string(),
+        },
+        GameEvent::PlayerJoined {
+            player_id: BOB,
+            name: "bob".to_string(),
+        },
+        GameEvent::PlaceTile {
+            player_id: BOB,
+            at: TilePosition::Board { at: 4 },
+        },
+        GameEvent::RequestTakeback { player_id: BOB },
+        GameEvent::AcceptTakeback { player_id: ALICE },
+        GameEvent::PlaceTile {
+            player_id: BOB,
+            at: TilePosition::Board { at: 0 },
+        },
+        GameEvent::PlaceTile {
+            player_id: ALICE,
+            at: TilePosition::Board { at: 3 },
+        },
+        GameEvent::PlaceTile {
+            player_id: BOB,
+            at: TilePosition::Board { at: 1 },
+        },
+        GameEvent::PlaceTile {
+            player_id: ALICE,
+            at: TilePosition::Board { at: 4 },
+        },
+        GameEvent::PlaceTile {
+            player_id: BOB,
+            at: TilePosition::Board { at: 2 },
+        },
+    ];
+    for event in events {
+        game_state.apply(event).unwrap();
+    }
+
+    game_state
+}
+
+#[test]
+fn rebuilds_a_game_from_its_history() {
+    let game_state = played_game();
+    assert_eq!(game_state.stage, Stage::Ended);
+    assert!(matches!(
+        game_state.history.last(),
+        Some(GameEvent::EndGame {
+            reason: EndGameReason::PlayerWon { winner: BOB }
+        })
+    ));
+
+    let rebuilt = GameState::from_events(game_state.rules, &game_state.history).unwrap();
+    assert_eq!(rebuilt, game_state);
+    assert!(game_state.is_consistent());
+}
+
+#[test]
+fn rewinds_to_any_point_in_the_history() {
+    let game_state = played_game();
+
+    assert_eq!(
+        game_state.state_at(0).unwrap(),
+        GameState::new(Rules::default())
+    );
+    // Both players joined, the game began and Bob placed his first tile
+    let after_first_move = game_state.state_at(4).unwrap();
+    assert_eq!(after_first_move.board[4], Tile::Tac);
+    assert_eq!(after_first_move.active_player_id, ALICE);
+    // The takeback empties the tile again and hands the turn back to Bob
+    let after_takeback = game_state.state_at(6).unwrap();
+    assert_eq!(after_takeback.board[4], Tile::Empty);
+    assert_eq!(after_takeback.active_player_id, BOB);
+
+    assert_eq!(game_state.state_at(usize::MAX).unwrap(), game_state);
+    for n in 0..=game_state.history.len() {
+        assert!(game_state.state_at(n).unwrap().is_consistent());
+    }
+}
+
+#[test]
+fn notices_states_that_drifted_from_their_history() {
+    let mut game_state = played_game();
+    game_state.board[8] = Tile::Tic;
+    assert!(!game_state.is_consistent());
+
+    let mut game_state = played_game();
+    game_state.history.pop();
+    assert!(!game_state.is_consistent());
+}
+
+#[test]
+fn rejects_histories_with_invalid_events() {
+    let events = [
+        GameEvent::PlayerJoined {
+            player_id: ALICE,
+            name: "alice".to_string(),
+        },
+        GameEvent::PlaceTile {
+            player_id: ALICE,
+            at: TilePosition::Board { at: 0 },
+        },
+    ];
+    assert_eq!(
+        GameState::from_events(Rules::default(), &events),
+        Err(ValidationError::WrongStage)
+    );
+}
+
+#[test]
+fn only_ends_games_that_are_being_played() {
+    let ended = played_game();
+    // Only Alice has joined, so the game hasn't begun yet
+    let not_begun = ended.state_at(1).unwrap();
+    assert_eq!(not_begun.stage, Stage::PreGame);
+
+    for game_state in [&not_begun, &ended] {
+        for reason in [
+            EndGameReason::Timeout { player_id: ALICE },
+            EndGameReason::PlayerLeft { player_id: ALICE },
+        ] {
+            assert_eq!(
+                game_state.validate(&GameEvent::EndGame { reason }),
+                Err(ValidationError::WrongStage)
+            );
+        }
+    }
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use common::new_game;
+use std::io::ErrorKind;
+use store::replay::Replay;
+use store::Rules;
+
+#[test]
+fn never_overwrites_a_saved_replay() {
+    let path =
+        std::env::temp_dir().join(format!("tictactussle-replay-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let replay = Replay::record(&new_game(Rules::default()));
+    replay.save(&path).unwrap();
+    let err = replay.save(&path).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(Replay::load(&path).unwrap(), replay);
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,63 @@
+use store::net::{ClientSession, Session};
+use store::{ClientMessage, GameState, ServerMessage, UserData};
+
+fn new_session() -> ClientSession {
+    let user_data = UserData {
+        name: "alice".to_string(),
+        room: None,
+        session_token: None,
+    };
+    ClientSession::new("127.0.0.1:5000".parse().unwrap(), user_data)
+}
+
+#[test]
+fn asks_for_the_leaderboard_once_welcomed() {
+    let mut session = new_session();
+    let mut game_state = GameState::default();
+    let welcome = ServerMessage::Welcome {
+        player_id: 7,
+        session_token: 42,
+    };
+
+    assert_eq!(
+        session.handle(&mut game_state, &welcome),
+        [ClientMessage::RequestLeaderboard]
+    );
+    assert_eq!(
+        session.session,
+        Some(Session {
+            player_id: 7,
+            session_token: 42
+        })
+    );
+}
+
+#[test]
+fn asks_for_a_snapshot_once_until_it_arrives() {
+    let mut session = new_session();
+    let mut game_state = GameState::default();
+    let agreeing = ServerMessage::StateChecksum(game_state.checksum());
+    let disagreeing = ServerMessage::StateChecksum(game_state.checksum().wrapping_add(1));
+
+    assert!(session.handle(&mut game_state, &agreeing).is_empty());
+    assert_eq!(
+        session.handle(&mut game_state, &disagreeing),
+        [ClientMessage::RequestResync]
+    );
+    assert!(session.handle(&mut game_state, &disagreeing).is_empty());
+
+    let snapshot = ServerMessage::StateSnapshot(GameState::default());
+    assert!(session.handle(&mut game_state, &snapshot).is_empty());
+    assert_eq!(
+        session.handle(&mut game_state, &disagreeing),
+        [ClientMessage::RequestResync]
+    );
+}
+
+#[test]
+fn only_reconnects_to_a_game_in_progress() {
+    let mut session = new_session();
+    assert!(session
+        .reconnect_as(&GameState::default(), "connection lost")
+        .is_err());
+}
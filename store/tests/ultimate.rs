@@ -0,0 +1,133 @@
+mod common;
+
+use common::{new_game, ALICE, BOB};
+use store::{
+    EndGameReason, GameEvent, GameState, PlayerId, Rules, Stage, Tile, TilePosition,
+    ValidationError,
+};
+
+/// Places a piece on the given tile of the given small board
+fn place(
+    game_state: &mut GameState,
+    player_id: PlayerId,
+    sub_board: usize,
+    cell: usize,
+) -> Result<Vec<GameEvent>, ValidationError> {
+    let at = TilePosition::SubBoard { sub_board, cell };
+    game_state.apply(GameEvent::PlaceTile { player_id, at })
+}
+
+#[test]
+fn addresses_tiles_by_small_board() {
+    let rules = Rules::ultimate();
+    assert!(rules.is_playable());
+    assert_eq!(rules.positions().len(), rules.tile_count());
+    for at in rules.positions() {
+        let (x, y) = rules.position_coordinates(at);
+        assert_eq!(rules.position(x as isize, y as isize), Some(at));
+    }
+
+    // The bottom left tile of the top right small board
+    assert_eq!(
+        rules.position(6, 6),
+        Some(TilePosition::SubBoard {
+            sub_board: 8,
+            cell: 0
+        })
+    );
+    assert_eq!(rules.position(9, 0), None);
+}
+
+#[test]
+fn rejects_tiles_addressed_like_a_classic_board() {
+    let mut game_state = new_game(Rules::ultimate());
+    assert_eq!(
+        game_state.apply(GameEvent::PlaceTile {
+            player_id: BOB,
+            at: TilePosition::Board { at: 0 }
+        }),
+        Err(ValidationError::OutOfBounds)
+    );
+    assert_eq!(
+        place(&mut game_state, BOB, 9, 0),
+        Err(ValidationError::OutOfBounds)
+    );
+}
+
+#[test]
+fn sends_the_opponent_to_the_matching_small_board() {
+    let mut game_state = new_game(Rules::ultimate());
+    assert_eq!(game_state.forced_sub_board, None);
+
+    // Playing the middle tile of a small board sends the opponent to the middle small board
+    place(&mut game_state, BOB, 0, 4).unwrap();
+    assert_eq!(game_state.forced_sub_board, Some(4));
+    assert_eq!(
+        place(&mut game_state, ALICE, 0, 0),
+        Err(ValidationError::WrongSubBoard)
+    );
+
+    place(&mut game_state, ALICE, 4, 0).unwrap();
+    assert_eq!(game_state.forced_sub_board, Some(0));
+}
+
+#[test]
+fn claims_small_boards_and_wins_on_the_big_board() {
+    let mut game_state = new_game(Rules::ultimate());
+    // Bob claims the top row of small boards, from right to left
+    let moves = [
+        (BOB, 7, 6),
+        (ALICE, 6, 3),
+        (BOB, 3, 4),
+        (ALICE, 4, 7),
+        (BOB, 7, 1),
+        (ALICE, 1, 8),
+        (BOB, 8, 5),
+        (ALICE, 5, 7),
+        (BOB, 7, 8),
+        (ALICE, 8, 7),
+        (BOB, 7, 4),
+        (ALICE, 4, 6),
+        (BOB, 6, 2),
+        (ALICE, 2, 7),
+        (BOB, 7, 0),
+    ];
+    for (player_id, sub_board, cell) in moves {
+        place(&mut game_state, player_id, sub_board, cell).unwrap();
+    }
+    assert_eq!(game_state.sub_boards[7].winner, Tile::Tac);
+    assert!(game_state.sub_boards[7].is_closed());
+
+    // Alice sends Bob to the board he just claimed, so he gets to pick any open board instead
+    place(&mut game_state, ALICE, 0, 7).unwrap();
+    assert_eq!(game_state.forced_sub_board, None);
+    assert_eq!(
+        place(&mut game_state, BOB, 7, 2),
+        Err(ValidationError::SubBoardClaimed)
+    );
+
+    let moves = [
+        (BOB, 8, 3),
+        (ALICE, 3, 6),
+        (BOB, 6, 5),
+        (ALICE, 5, 6),
+        (BOB, 6, 8),
+        (ALICE, 8, 8),
+    ];
+    for (player_id, sub_board, cell) in moves {
+        place(&mut game_state, player_id, sub_board, cell).unwrap();
+    }
+    assert_eq!(game_state.stage, Stage::InGame);
+
+    let events = place(&mut game_state, BOB, 8, 4).unwrap();
+    assert_eq!(
+        events.last(),
+        Some(&GameEvent::EndGame {
+            reason: EndGameReason::PlayerWon { winner: BOB }
+        })
+    );
+    assert!(game_state.sub_boards[6..]
+        .iter()
+        .all(|board| board.winner == Tile::Tac));
+    assert!(game_state.is_consistent());
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{new_game, BOB};
+use store::{GameState, PlayerId, Rules};
+
+/// A game on a board wider than it is high, where it takes fewer tiles in a row to win than fit on a row
+fn wide_game() -> GameState {
+    new_game(Rules {
+        width: 5,
+        height: 4,
+        win_length: 3,
+        ..Rules::default()
+    })
+}
+
+/// Who has won once Bob has pieces on the tiles at the given (x, y) coordinates
+fn winner_with_bob_on(tiles: &[(isize, isize)]) -> Option<PlayerId> {
+    let mut game_state = wide_game();
+    let piece = game_state.get_player_tile(&BOB).unwrap();
+    for (x, y) in tiles {
+        let at = game_state.rules.index(*x, *y).unwrap();
+        game_state.board[at] = piece;
+    }
+
+    game_state.determine_winner()
+}
+
+#[test]
+fn finds_lines_in_every_direction() {
+    assert_eq!(winner_with_bob_on(&[(1, 2), (2, 2), (3, 2)]), Some(BOB));
+    assert_eq!(winner_with_bob_on(&[(4, 1), (4, 2), (4, 3)]), Some(BOB));
+    assert_eq!(winner_with_bob_on(&[(0, 0), (1, 1), (2, 2)]), Some(BOB));
+    assert_eq!(winner_with_bob_on(&[(2, 3), (3, 2), (4, 1)]), Some(BOB));
+}
+
+#[test]
+fn needs_the_full_win_length() {
+    assert_eq!(winner_with_bob_on(&[]), None);
+    assert_eq!(winner_with_bob_on(&[(3, 0), (4, 0)]), None);
+    assert_eq!(winner_with_bob_on(&[(0, 0), (2, 0), (4, 0)]), None);
+    assert_eq!(winner_with_bob_on(&[(0, 3), (1, 2), (3, 0)]), None);
+}
+
+#[test]
+fn does_not_count_lines_that_wrap_around_the_edge() {
+    // Next to each other in the tile order, but split over the end of one row and the start of the next
+    assert_eq!(winner_with_bob_on(&[(3, 0), (4, 0), (0, 1)]), None);
+    assert_eq!(winner_with_bob_on(&[(4, 1), (0, 2), (1, 2)]), None);
+    // A diagonal running off the right edge
+    assert_eq!(winner_with_bob_on(&[(3, 0), (4, 1), (0, 2)]), None);
+}
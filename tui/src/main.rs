@@ -0,0 +1,608 @@
+use clap::Parser;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode};
+use crossterm::style::{Color, Print, ResetColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use renet::RenetClient;
+use std::io::{stdout, Stdout, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use store::config::{read_config, ClientConfig};
+use store::net::{self, ClientSession, CHAT_CHANNEL, GAME_CHANNEL};
+use store::{
+    ChatMessage, ClientMessage, EndGameReason, GameState, LeaderboardEntry, PlayerId,
+    ServerMessage, Stage, Tile, UserData, Variant, SUB_BOARD_SIZE,
+};
+
+// How long to wait for a key press before updating the connection and redrawing
+const TICK: Duration = Duration::from_millis(50);
+// How many chat messages are shown below the board at once. Page up and down scroll through older ones
+const CHAT_LINES: usize = 5;
+
+/// Everything the terminal client knows, kept up to date from the messages the server sends
+struct App {
+    connection: ClientSession,
+    game_state: GameState,
+    leaderboard: Vec<LeaderboardEntry>,
+    chat: Vec<ChatMessage>,
+    /// How many of the newest chat messages are hidden because the player scrolled back
+    chat_scrolled_back: usize,
+    /// The tile the player is about to place a piece on
+    cursor: (usize, usize),
+    /// Something to tell the player, like why the server rejected their move
+    notice: Option<String>,
+    /// The turn we are counting down the clock for and when we heard about it starting
+    turn: Option<((Stage, PlayerId, usize), Instant)>,
+    quit: bool,
+}
+
+/// Play TicTacTussle online from the terminal.
+/// The host, port and name can also be set in a config file. Anything passed on the command line wins.
+#[derive(Debug, Parser)]
+#[command(name = "tui")]
+struct Args {
+    /// The name to play as
+    name: Option<String>,
+    /// The room to join. Without one you are paired up with whoever else is waiting for an opponent
+    room: Option<String>,
+    /// A toml file with the host, port and name to use
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// The host the server is running on
+    #[arg(long)]
+    host: Option<String>,
+    /// The port the server is listening on
+    #[arg(long)]
+    port: Option<u16>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let config: ClientConfig = read_config(args.config.as_deref())?;
+    let config = ClientConfig {
+        host: args.host.unwrap_or(config.host),
+        port: args.port.unwrap_or(config.port),
+        name: args.name.or(config.name),
+    };
+    let name = match config.name.clone() {
+        Some(name) => name,
+        None => anyhow::bail!(
+            "No name to play as, pass one as the first argument or set name in the config file"
+        ),
+    };
+    let user_data = UserData {
+        name,
+        room: args.room,
+        session_token: None,
+    };
+    let connection = ClientSession::new(config.server_addr()?, user_data);
+    let mut client = connection.connect()?;
+    let mut app = App::new(connection);
+
+    let _terminal = RawTerminal::enter()?;
+    let mut stdout = stdout();
+    let mut last_updated_at = Instant::now();
+    while !app.quit {
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                app.handle_key(key.code, &mut client);
+            }
+        }
+
+        let now = Instant::now();
+        if let Err(err) = client.update(now - last_updated_at) {
+            app.handle_renet_error(err, &mut client)?;
+        }
+        last_updated_at = now;
+
+        if client.is_connected() {
+            app.connection.mark_connected();
+            app.receive_messages(&mut client);
+            client.send_packets()?;
+        }
+
+        app.draw(&mut stdout)?;
+    }
+
+    Ok(())
+}
+
+/// Puts the terminal in raw mode on a screen of its own, and restores it when dropped.
+/// Restoring on drop means the terminal is left usable even if the client panics.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter() -> anyhow::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout(), EnterAlternateScreen, Hide)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = execute!(stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+impl App {
+    fn new(connection: ClientSession) -> Self {
+        Self {
+            connection,
+            game_state: GameState::default(),
+            leaderboard: Vec::new(),
+            chat: Vec::new(),
+            chat_scrolled_back: 0,
+            cursor: (0, 0),
+            notice: None,
+            turn: None,
+            quit: false,
+        }
+    }
+
+    ////////// INPUT //////////
+    fn handle_key(&mut self, key: KeyCode, client: &mut RenetClient) {
+        let rules = self.game_state.rules;
+        let (x, y) = self.cursor;
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            // The board is drawn with row 0 at the bottom, so up moves to a higher row
+            KeyCode::Up => self.cursor.1 = (y + 1).min(rules.height - 1),
+            KeyCode::Down => self.cursor.1 = y.saturating_sub(1),
+            KeyCode::Left => self.cursor.0 = x.saturating_sub(1),
+            KeyCode::Right => self.cursor.0 = (x + 1).min(rules.width - 1),
+            KeyCode::Enter | KeyCode::Char(' ') => {
+                if let Some(at) = rules.position(x as isize, y as isize) {
+                    self.send(client, ClientMessage::PlaceTile { at });
+                }
+            }
+            // The digits are laid out like a numpad, so 1 is the bottom left tile and 9 the top right.
+            // Only the bottom left corner of bigger boards can be reached this way.
+            KeyCode::Char(digit @ '1'..='9') => {
+                let digit = digit as usize - '1' as usize;
+                if let Some(at) = rules.position((digit % 3) as isize, (digit / 3) as isize) {
+                    self.cursor = rules.position_coordinates(at);
+                    self.send(client, ClientMessage::PlaceTile { at });
+                }
+            }
+            KeyCode::Char('r') if self.game_state.stage == Stage::Ended => {
+                let message = match self.game_state.rematch_requested_by {
+                    Some(player_id) if Some(player_id) != self.player_id() => {
+                        ClientMessage::AcceptRematch
+                    }
+                    _ => ClientMessage::RequestRematch,
+                };
+                self.send(client, message);
+            }
+            KeyCode::Char('u') if self.game_state.stage == Stage::InGame => {
+                self.send(client, ClientMessage::RequestTakeback);
+            }
+            KeyCode::Char('y') if self.game_state.takeback_requested_by.is_some() => {
+                self.send(client, ClientMessage::AcceptTakeback);
+            }
+            KeyCode::Char('n') if self.game_state.takeback_requested_by.is_some() => {
+                self.send(client, ClientMessage::DeclineTakeback);
+            }
+            KeyCode::Char('d') if self.game_state.stage == Stage::InGame => {
+                let message = match self.game_state.draw_offered_by {
+                    Some(player_id) if Some(player_id) != self.player_id() => {
+                        ClientMessage::AcceptDraw
+                    }
+                    _ => ClientMessage::OfferDraw,
+                };
+                self.send(client, message);
+            }
+            KeyCode::Char('x') if self.game_state.stage == Stage::InGame => {
+                self.send(client, ClientMessage::Resign);
+            }
+            KeyCode::PageUp => {
+                let oldest = self.chat.len().saturating_sub(CHAT_LINES);
+                self.chat_scrolled_back = (self.chat_scrolled_back + 1).min(oldest);
+            }
+            KeyCode::PageDown => {
+                self.chat_scrolled_back = self.chat_scrolled_back.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+
+    fn send(&mut self, client: &mut RenetClient, message: ClientMessage) {
+        // Spectators only get to watch, the server won't accept anything from them
+        if self.connection.session.is_none() || !client.is_connected() {
+            return;
+        }
+
+        self.notice = None;
+        client.send_message(GAME_CHANNEL, bincode::serialize(&message).unwrap());
+    }
+
+    fn player_id(&self) -> Option<PlayerId> {
+        self.connection
+            .session
+            .as_ref()
+            .map(|session| session.player_id)
+    }
+
+    ////////// RENET NETWORKING //////////
+    fn receive_messages(&mut self, client: &mut RenetClient) {
+        while let Some(message) = client.receive_message(GAME_CHANNEL) {
+            let message: ServerMessage = bincode::deserialize(&message).unwrap();
+            for reply in self.connection.handle(&mut self.game_state, &message) {
+                client.send_message(GAME_CHANNEL, bincode::serialize(&reply).unwrap());
+            }
+
+            match message {
+                ServerMessage::Leaderboard(entries) => self.leaderboard = entries,
+                ServerMessage::StateSnapshot(_) | ServerMessage::GameEvent(_) => {
+                    self.clamp_cursor()
+                }
+                ServerMessage::EventRejected { event: _, reason } => {
+                    self.notice = Some(reason.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        while let Some(message) = client.receive_message(CHAT_CHANNEL) {
+            let message: ChatMessage = bincode::deserialize(&message).unwrap();
+            self.chat.push(message);
+            // Keep showing the same messages to a player who is reading back through the chat
+            if self.chat_scrolled_back > 0 {
+                self.chat_scrolled_back += 1;
+            }
+        }
+    }
+
+    /// Keeps the cursor on the board, which may have changed shape
+    fn clamp_cursor(&mut self) {
+        let rules = self.game_state.rules;
+        self.cursor = (
+            self.cursor.0.min(rules.width.saturating_sub(1)),
+            self.cursor.1.min(rules.height.saturating_sub(1)),
+        );
+    }
+
+    // If we lose the connection mid game we try to get back in using our session token.
+    // On any other network error we give up, just like the graphical client.
+    fn handle_renet_error(
+        &mut self,
+        err: renet::RenetError,
+        client: &mut RenetClient,
+    ) -> anyhow::Result<()> {
+        let user_data = self.connection.reconnect_as(&self.game_state, err)?;
+        self.notice = Some("Connection lost, reconnecting...".to_string());
+        // If we can't even get a connect token the server might be restarting. We'll try again next tick
+        if let Ok(new_client) = net::new_renet_client(self.connection.server_addr, &user_data) {
+            *client = new_client;
+        }
+
+        Ok(())
+    }
+
+    ////////// RENDERING //////////
+    fn draw(&mut self, stdout: &mut Stdout) -> anyhow::Result<()> {
+        queue!(stdout, Clear(ClearType::All))?;
+        let mut lines = Lines { stdout, row: 0 };
+
+        let title = match &self.connection.user_data.room {
+            Some(room) => format!(
+                "TicTacTussle <{}> in {}",
+                self.connection.user_data.name, room
+            ),
+            None => format!("TicTacTussle <{}>", self.connection.user_data.name),
+        };
+        lines.print(&title, Color::Reset)?;
+        lines.skip();
+
+        match self.game_state.stage {
+            Stage::PreGame => self.draw_waiting(&mut lines)?,
+            Stage::InGame | Stage::Ended => {
+                self.draw_players(&mut lines)?;
+                lines.skip();
+                self.draw_board(&mut lines)?;
+                lines.skip();
+                lines.print(&self.status(), Color::Reset)?;
+            }
+        }
+
+        if let Some(notice) = &self.notice {
+            lines.print(
+                notice,
+                Color::Rgb {
+                    r: 251,
+                    g: 73,
+                    b: 52,
+                },
+            )?;
+        }
+
+        if !self.chat.is_empty() {
+            lines.skip();
+            let end = self.chat.len() - self.chat_scrolled_back;
+            for message in &self.chat[end.saturating_sub(CHAT_LINES)..end] {
+                match &message.sender {
+                    Some(sender) => lines.print(&format!("{}: {}", sender, message.text), TEXT)?,
+                    None => lines.print(&message.text, GREY)?,
+                }
+            }
+            if self.chat_scrolled_back > 0 {
+                lines.print(
+                    &format!("({} newer, page down)", self.chat_scrolled_back),
+                    GREY,
+                )?;
+            }
+        }
+
+        lines.skip();
+        lines.print(
+            "arrows + enter or 1-9 to place, u to take back, d for a draw, x to resign, r for rematch, pgup/pgdn for chat, q to quit",
+            GREY,
+        )?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn draw_waiting(&self, lines: &mut Lines) -> anyhow::Result<()> {
+        lines.print("Waiting for an opponent...", Color::Reset)?;
+        if self.leaderboard.is_empty() {
+            return Ok(());
+        }
+
+        lines.skip();
+        lines.print("Leaderboard", TEXT)?;
+        for (place, entry) in self.leaderboard.iter().enumerate() {
+            lines.print(
+                &format!(
+                    "{:>2}. {:<16} {:>4}  {}-{}-{}",
+                    place + 1,
+                    entry.name,
+                    entry.rating,
+                    entry.wins,
+                    entry.losses,
+                    entry.draws
+                ),
+                Color::Reset,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn draw_players(&mut self, lines: &mut Lines) -> anyhow::Result<()> {
+        // A new turn starts whenever a piece is placed or a game begins. The server keeps the real time,
+        // we just count down from when we heard about the turn starting.
+        let now = Instant::now();
+        let pieces = self.game_state.piece_count();
+        let (stage, active_player_id) = (self.game_state.stage, self.game_state.active_player_id);
+        let current_turn = (stage, active_player_id, pieces);
+        let turn_started_at = match self.turn {
+            Some((known_turn, started_at)) if known_turn == current_turn => started_at,
+            _ => {
+                self.turn = Some((current_turn, now));
+                now
+            }
+        };
+
+        for (player_id, player) in &self.game_state.players {
+            let active = stage == Stage::InGame && *player_id == active_player_id;
+            // Only the clock of the player whose turn it is is running
+            let elapsed = if active {
+                now - turn_started_at
+            } else {
+                Duration::ZERO
+            };
+            let clock = match self.game_state.time_left_for_move(player_id, elapsed) {
+                Some(time_left) => {
+                    let seconds = time_left.as_secs_f32().ceil() as u64;
+                    format!(" {}:{:02}", seconds / 60, seconds % 60)
+                }
+                None => String::new(),
+            };
+            let you = if Some(*player_id) == self.player_id() {
+                " (you)"
+            } else {
+                ""
+            };
+            let disconnected = if player.connected {
+                ""
+            } else {
+                " [disconnected]"
+            };
+
+            lines.print(
+                &format!(
+                    "{} {} {}{}{}{}",
+                    if active { ">" } else { " " },
+                    piece_symbol(player.piece),
+                    player.name,
+                    you,
+                    clock,
+                    disconnected
+                ),
+                piece_color(player.piece),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn draw_board(&self, lines: &mut Lines) -> anyhow::Result<()> {
+        let rules = self.game_state.rules;
+        let show_cursor =
+            self.connection.session.is_some() && self.game_state.stage == Stage::InGame;
+        // The small boards of ultimate tic-tac-toe are set apart, and the tiles the active player can pick are lit up
+        let is_ultimate = rules.variant == Variant::Ultimate;
+        // Row 0 is the bottom of the board, same as in the graphical client
+        for y in (0..rules.height).rev() {
+            if is_ultimate && y % SUB_BOARD_SIZE == SUB_BOARD_SIZE - 1 && y + 1 < rules.height {
+                lines.row += 1;
+            }
+            queue!(lines.stdout, MoveTo(2, lines.row))?;
+            for x in 0..rules.width {
+                if is_ultimate && x % SUB_BOARD_SIZE == 0 && x > 0 {
+                    queue!(
+                        lines.stdout,
+                        SetForegroundColor(GREY),
+                        Print("|"),
+                        ResetColor
+                    )?;
+                }
+
+                let at = rules.position(x as isize, y as isize).unwrap();
+                let tile = self.game_state.tile(at).unwrap();
+                let (left, right) = if show_cursor && self.cursor == (x, y) {
+                    ("[", "]")
+                } else {
+                    (" ", " ")
+                };
+                let color = if is_ultimate && show_cursor && self.game_state.can_place_at(at) {
+                    TEXT
+                } else {
+                    piece_color(tile)
+                };
+                queue!(
+                    lines.stdout,
+                    Print(left),
+                    SetForegroundColor(color),
+                    Print(piece_symbol(tile)),
+                    ResetColor,
+                    Print(right)
+                )?;
+            }
+            lines.row += 1;
+        }
+        Ok(())
+    }
+
+    /// A line saying what is going on in the game
+    fn status(&self) -> String {
+        let name_of = |player_id: &PlayerId| {
+            self.game_state
+                .players
+                .get(player_id)
+                .map(|player| player.name.clone())
+                .unwrap_or_default()
+        };
+
+        match self.game_state.stage {
+            Stage::PreGame => String::new(),
+            Stage::InGame if self.connection.spectating => "Spectating".to_string(),
+            Stage::InGame => match self.game_state.takeback_requested_by {
+                Some(player_id) if Some(player_id) == self.player_id() => {
+                    "Waiting for an answer to your takeback...".to_string()
+                }
+                Some(player_id) => format!(
+                    "{} wants to take back their last move. Press y to let them or n to play on",
+                    name_of(&player_id)
+                ),
+                None => match self.game_state.draw_offered_by {
+                    Some(player_id) if Some(player_id) != self.player_id() => format!(
+                        "{} offers a draw. Press d to accept or make a move to play on",
+                        name_of(&player_id)
+                    ),
+                    _ if Some(self.game_state.active_player_id) == self.player_id() => {
+                        "Your turn".to_string()
+                    }
+                    _ => format!("Waiting for {}", name_of(&self.game_state.active_player_id)),
+                },
+            },
+            Stage::Ended => {
+                let reason = self
+                    .game_state
+                    .history
+                    .iter()
+                    .rev()
+                    .find_map(|event| match event {
+                        store::GameEvent::EndGame { reason } => Some(*reason),
+                        _ => None,
+                    });
+                let result = match reason {
+                    Some(EndGameReason::PlayerWon { winner }) => {
+                        format!("{} won!", name_of(&winner))
+                    }
+                    Some(EndGameReason::Draw) => "It's a draw!".to_string(),
+                    Some(EndGameReason::DrawAgreed) => "The players agreed to a draw".to_string(),
+                    Some(EndGameReason::PlayerLeft { player_id }) => {
+                        format!("{} left the game", name_of(&player_id))
+                    }
+                    Some(EndGameReason::Timeout { player_id }) => {
+                        format!("{} ran out of time!", name_of(&player_id))
+                    }
+                    Some(EndGameReason::Resigned { player_id }) => {
+                        format!("{} resigned!", name_of(&player_id))
+                    }
+                    None => "The game is over".to_string(),
+                };
+                let rematch = match self.game_state.rematch_requested_by {
+                    _ if self.connection.spectating => "",
+                    Some(player_id) if Some(player_id) == self.player_id() => {
+                        " Waiting for a rematch..."
+                    }
+                    Some(_) => " Press r to accept a rematch",
+                    None => " Press r for a rematch",
+                };
+                format!("{}{}", result, rematch)
+            }
+        }
+    }
+}
+
+/// Prints one line after another
+struct Lines<'a> {
+    stdout: &'a mut Stdout,
+    row: u16,
+}
+
+impl Lines<'_> {
+    fn print(&mut self, text: &str, color: Color) -> anyhow::Result<()> {
+        queue!(
+            self.stdout,
+            MoveTo(0, self.row),
+            SetForegroundColor(color),
+            Print(text),
+            ResetColor
+        )?;
+        self.row += 1;
+        Ok(())
+    }
+
+    fn skip(&mut self) {
+        self.row += 1;
+    }
+}
+
+const TEXT: Color = Color::Rgb {
+    r: 235,
+    g: 219,
+    b: 178,
+};
+const GREY: Color = Color::Rgb {
+    r: 146,
+    g: 131,
+    b: 116,
+};
+
+fn piece_symbol(tile: Tile) -> &'static str {
+    match tile {
+        Tile::Empty => ".",
+        Tile::Tic => "X",
+        Tile::Tac => "O",
+    }
+}
+
+// Same colors as the pieces in the graphical client
+fn piece_color(tile: Tile) -> Color {
+    match tile {
+        Tile::Empty => GREY,
+        Tile::Tic => Color::Rgb {
+            r: 69,
+            g: 133,
+            b: 136,
+        },
+        Tile::Tac => Color::Rgb {
+            r: 214,
+            g: 93,
+            b: 14,
+        },
+    }
+}